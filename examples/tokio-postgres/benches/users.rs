@@ -0,0 +1,110 @@
+//! Benchmarks for looking up and listing users, and for reading users from
+//! rows.
+//!
+//! The handlers run against the mock database, so the numbers measure our
+//! code rather than a Postgres server and don't vary with its load. Reading
+//! users from rows needs a real row, so that is skipped unless
+//! `TEST_DATABASE_URL` is set.
+//!
+//! Run with
+//!
+//! ```not_rust
+//! cargo bench -p example-tokio-postgres --features mock-db
+//! ```
+//!
+//! or add `-- --test` to run every benchmark just once, checking they still
+//! work without timing them, as CI should.
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Method, Request, StatusCode},
+    routing::BoxRoute,
+    Router,
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use example_tokio_postgres::bench;
+use tokio::runtime::Runtime;
+use tokio_postgres::NoTls;
+use tower::ServiceExt;
+
+/// How many users the handlers have to choose from.
+const USERS: usize = 100;
+
+async fn send(app: Router<BoxRoute>, method: Method, uri: &str, body: Body) -> (StatusCode, Bytes) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    (
+        status,
+        hyper::body::to_bytes(response.into_body()).await.unwrap(),
+    )
+}
+
+fn handlers(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    // in the runtime, since building the app spawns tasks
+    let app = rt.block_on(async {
+        let app = bench::mock_app();
+        for i in 0..USERS {
+            let body = format!(r#"{{"name": "user {}", "age": {}}}"#, i, i % 100);
+            let (status, _) = send(app.clone(), Method::POST, "/users", Body::from(body)).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        app
+    });
+
+    let get = |uri: &'static str, expected: StatusCode| {
+        let app = app.clone();
+        move || {
+            let app = app.clone();
+            async move {
+                let (status, body) = send(app, Method::GET, uri, Body::empty()).await;
+                assert_eq!(status, expected);
+                body
+            }
+        }
+    };
+
+    c.bench_function("GET /:id", |b| {
+        b.to_async(&rt).iter(get("/1", StatusCode::FOUND))
+    });
+    c.bench_function("GET /users", |b| {
+        b.to_async(&rt)
+            .iter(get("/users?limit=100", StatusCode::OK))
+    });
+}
+
+fn rows(c: &mut Criterion) {
+    let url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("skipping reading users from rows, TEST_DATABASE_URL isn't set");
+            return;
+        }
+    };
+
+    let rt = Runtime::new().unwrap();
+    let row = rt.block_on(async {
+        let (client, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+        tokio::spawn(connection);
+        client
+            .query_one(
+                "select 1::integer as id, 'alice'::text as name, 30::bigint as age",
+                &[],
+            )
+            .await
+            .unwrap()
+    });
+
+    c.bench_function("User::from_row", |b| {
+        b.iter(|| bench::user_from_row(black_box(&row)).unwrap())
+    });
+}
+
+criterion_group!(benches, handlers, rows);
+criterion_main!(benches);
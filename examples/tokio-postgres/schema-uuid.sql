@@ -0,0 +1,15 @@
+-- like `schema.sql` but with UUIDs as ids, for `UUID_IDS=true`
+create table if not exists users (
+    id uuid primary key default gen_random_uuid(),
+    name text not null,
+    age integer not null check (age >= 0),
+    -- set when a user is soft-deleted, they are purged after
+    -- `SOFT_DELETE_RETENTION_DAYS`
+    deleted_at timestamptz,
+    -- the `Idempotency-Key` the user was created with, so retried creates
+    -- find it instead of creating it again
+    idempotency_key text unique
+);
+
+-- for tables created before `idempotency_key` was added
+alter table users add column if not exists idempotency_key text unique;
@@ -0,0 +1,14 @@
+create table if not exists users (
+    id serial primary key,
+    name text not null,
+    age integer not null check (age >= 0),
+    -- set when a user is soft-deleted, they are purged after
+    -- `SOFT_DELETE_RETENTION_DAYS`
+    deleted_at timestamptz,
+    -- the `Idempotency-Key` the user was created with, so retried creates
+    -- find it instead of creating it again
+    idempotency_key text unique
+);
+
+-- for tables created before `idempotency_key` was added
+alter table users add column if not exists idempotency_key text unique;
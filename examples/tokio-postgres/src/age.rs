@@ -0,0 +1,78 @@
+//! A user's age, which can only be constructed if it is in range.
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+/// An age between 0 and [`Age::MAX`].
+///
+/// Request bodies use this rather than a plain integer so an out of range age
+/// is rejected while deserializing, and code handling one never has to check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Age(i32);
+
+impl Age {
+    pub const MAX: i32 = 150;
+
+    pub fn try_new(age: i64) -> Result<Self, AgeOutOfRange> {
+        if (0..=i64::from(Self::MAX)).contains(&age) {
+            Ok(Self(age as i32))
+        } else {
+            Err(AgeOutOfRange)
+        }
+    }
+}
+
+impl From<Age> for i64 {
+    fn from(age: Age) -> Self {
+        age.0.into()
+    }
+}
+
+impl<'de> Deserialize<'de> for Age {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let age = i64::deserialize(deserializer)?;
+        Self::try_new(age).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug)]
+pub struct AgeOutOfRange;
+
+impl fmt::Display for AgeOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "age must be between 0 and {}", Age::MAX)
+    }
+}
+
+impl std::error::Error for AgeOutOfRange {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_ages_in_range_can_be_constructed() {
+        for age in vec![0, 30, 150] {
+            assert_eq!(i64::from(Age::try_new(age).unwrap()), age);
+        }
+        for age in vec![-1, 151, i64::from(i32::MAX) + 1] {
+            assert!(Age::try_new(age).is_err(), "{}", age);
+        }
+    }
+
+    #[test]
+    fn out_of_range_ages_are_rejected_when_deserializing() {
+        assert_eq!(serde_json::from_str::<Age>("30").unwrap(), Age(30));
+
+        let err = serde_json::from_str::<Age>("200").unwrap_err();
+        assert!(
+            err.to_string().starts_with("age must be between 0 and 150"),
+            "{}",
+            err
+        );
+        assert!(serde_json::from_str::<Age>("\"30\"").is_err());
+    }
+}
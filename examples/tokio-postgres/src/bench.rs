@@ -0,0 +1,24 @@
+//! What the benchmarks in `benches/` need from the crate, which is otherwise
+//! private.
+
+use crate::User;
+use tokio_postgres::Row;
+
+/// The app, keeping users in memory rather than in Postgres and without the
+/// cache in front of them, so every request gets to the repository.
+#[cfg(feature = "mock-db")]
+pub fn mock_app() -> axum::Router<axum::routing::BoxRoute> {
+    use crate::{repository::MockUsers, AppState, Config};
+    use std::{sync::Arc, time::Duration};
+
+    let config = Config {
+        cache_ttl: Duration::from_secs(0),
+        ..Config::from_env().unwrap()
+    };
+    crate::app(Arc::new(AppState::mock(config, MockUsers::default())))
+}
+
+/// Read a user from `row`, as [`User::from_row`] does.
+pub fn user_from_row(row: &Row) -> Result<impl std::fmt::Debug, String> {
+    User::from_row(row).map_err(|err| err.message().to_string())
+}
@@ -0,0 +1,241 @@
+//! Failing fast while the database is unreachable.
+//!
+//! Without this every request waits `PG_POOL_TIMEOUT_SECS` for a connection
+//! that isn't coming, and hits the database with connection attempts the
+//! moment it starts to come back.
+
+use crate::error::AppError;
+use serde::Serialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests use the database as usual.
+    Closed,
+    /// Requests fail right away until the cooldown is over.
+    Open,
+    /// The cooldown is over and a request is checking whether the database
+    /// is back.
+    HalfOpen,
+}
+
+/// Opens after `BREAKER_THRESHOLD` connection attempts in a row failed, and
+/// then fails requests with `503 Service Unavailable` for
+/// `BREAKER_COOLDOWN_SECS`.
+///
+/// After that the next request gets to try the database. If it gets a
+/// connection the breaker closes again, if connecting fails it stays open for
+/// another cooldown.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Connection attempts that failed since the last success.
+    failures: u32,
+    /// Set while open, requests fail until then.
+    retry_at: Option<Instant>,
+    /// Set once a request was let through to probe the database.
+    probing: bool,
+    /// How often it opened since we started.
+    opened: u64,
+}
+
+impl CircuitBreaker {
+    /// A breaker that opens after `threshold` failures, or never if it is
+    /// zero.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Whether a request may use the database.
+    ///
+    /// Once the cooldown is over one request is let through to probe the
+    /// database. The others keep failing until it succeeds, or for another
+    /// cooldown in case it never finishes.
+    pub fn check(&self) -> Result<(), AppError> {
+        let mut inner = self.inner.lock().unwrap();
+        let retry_at = match inner.retry_at {
+            Some(retry_at) => retry_at,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        if now < retry_at {
+            return Err(AppError::unavailable(
+                "database is unavailable",
+                retry_at - now,
+            ));
+        }
+
+        tracing::info!("probing whether the database is back");
+        inner.retry_at = Some(now + self.cooldown);
+        inner.probing = true;
+        Ok(())
+    }
+
+    /// Record that we got a connection, closing the breaker.
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.retry_at.take().is_some() {
+            tracing::info!("database is back, closing circuit breaker");
+        }
+        inner.failures = 0;
+        inner.probing = false;
+    }
+
+    /// Record that connecting to the database failed, opening the breaker
+    /// once that happened `threshold` times in a row.
+    pub fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = inner.failures.saturating_add(1);
+        if inner.failures < self.threshold {
+            return;
+        }
+
+        if inner.retry_at.is_none() {
+            inner.opened += 1;
+            tracing::warn!(
+                failures = inner.failures,
+                cooldown = ?self.cooldown,
+                "database is unreachable, opening circuit breaker"
+            );
+        }
+        inner.retry_at = Some(Instant::now() + self.cooldown);
+        inner.probing = false;
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+        match inner.retry_at {
+            None => BreakerState::Closed,
+            Some(_) if inner.probing => BreakerState::HalfOpen,
+            Some(_) => BreakerState::Open,
+        }
+    }
+
+    /// How often the breaker opened since we started.
+    pub fn opened(&self) -> u64 {
+        self.inner.lock().unwrap().opened
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app,
+        db::Manager,
+        test_helpers::{send, test_db},
+        AppState, Config,
+    };
+    use axum::http::{Method, StatusCode};
+    use std::sync::Arc;
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_millis(50));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.check().is_ok());
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(
+            breaker.check().unwrap_err().message(),
+            "database is unavailable"
+        );
+
+        // one probe after the cooldown, which fails, so we wait again
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.check().is_err());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.opened(), 1);
+    }
+
+    fn breaker_config() -> Config {
+        Config {
+            breaker_threshold: 2,
+            breaker_cooldown: Duration::from_millis(500),
+            pool_timeout: Duration::from_millis(300),
+            ..Config::from_env().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_fail_fast_while_the_database_is_unreachable() {
+        let state = AppState::starting(breaker_config());
+        // nothing listens on port 1
+        let manager = Manager::new("host=127.0.0.1 port=1 user=postgres".parse().unwrap())
+            .breaker(state.breaker.clone());
+        let pool = bb8::Pool::builder()
+            .connection_timeout(state.config.pool_timeout)
+            .build_unchecked(manager);
+        state.set_pool(pool);
+        let state = Arc::new(state);
+
+        // waits for the pool to give up
+        let (status, _) = send(app(state.clone()), Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(state.breaker.state(), BreakerState::Open);
+
+        let start = Instant::now();
+        let (status, _) = send(app(state), Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn breaker_closes_once_a_probe_gets_a_connection() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .execute("insert into users (name, age) values ('alice', 30)", &[])
+            .await
+            .unwrap();
+        let state = db.state_with(breaker_config()).await;
+        state.breaker.record_failure();
+        state.breaker.record_failure();
+
+        let (status, _) = send(app(state.clone()), Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let (status, _) = send(app(state.clone()), Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(state.breaker.state(), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,847 @@
+//! Connection pool setup.
+
+use crate::{breaker::CircuitBreaker, error::AppError};
+use axum::async_trait;
+use bb8::{ManageConnection, Pool, PooledConnection};
+use bb8_postgres::PostgresConnectionManager;
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio_postgres::{
+    types::{FromSql, ToSql, Type},
+    Client, Error, NoTls, Row, Statement,
+};
+use uuid::Uuid;
+
+pub type ConnectionPool = Pool<Manager>;
+
+pub type Conn = PooledConnection<'static, Manager>;
+
+/// How often [`Connection::with_transaction`] runs a transaction again after
+/// it conflicted with another one.
+const MAX_TRANSACTION_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry, doubling for every one after.
+const TRANSACTION_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Manages connections for our pool.
+///
+/// This wraps bb8-postgres' `PostgresConnectionManager` so our connections
+/// can carry some extra state, like their prepared statements.
+pub struct Manager {
+    inner: PostgresConnectionManager<NoTls>,
+    search_path: Option<String>,
+    statement_timeout: Option<Duration>,
+    stats: Arc<ConnectionStats>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl Manager {
+    pub fn new(config: tokio_postgres::Config) -> Self {
+        Self {
+            inner: PostgresConnectionManager::new(config, NoTls),
+            search_path: None,
+            statement_timeout: None,
+            stats: Arc::default(),
+            breaker: None,
+        }
+    }
+
+    /// Count the connections we create and discard in `stats`, since the pool
+    /// doesn't give us a way back to its manager.
+    pub fn stats(mut self, stats: Arc<ConnectionStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Tell `breaker` about connection attempts that fail.
+    pub fn breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Set the `search_path` of every connection to `schema`, so unqualified
+    /// table names resolve to the tables in that schema.
+    ///
+    /// # Panics
+    ///
+    /// If `schema` isn't a plain lowercase identifier. Use [`validate_schema`]
+    /// to check values coming from configuration.
+    pub fn search_path(mut self, schema: impl Into<String>) -> Self {
+        let schema = schema.into();
+        assert!(is_identifier(&schema), "invalid schema name {:?}", schema);
+        self.search_path = Some(schema);
+        self
+    }
+
+    /// Abort any statement that takes longer than `timeout`.
+    ///
+    /// Use [`Connection::with_statement_timeout`] for queries that need
+    /// longer.
+    pub fn statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+}
+
+#[async_trait]
+impl ManageConnection for Manager {
+    type Connection = Connection;
+    type Error = Error;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        let client = self.inner.connect().await.map_err(|err| {
+            tracing::warn!(%err, "failed to establish database connection");
+            if let Some(breaker) = &self.breaker {
+                breaker.record_failure();
+            }
+            err
+        })?;
+
+        // `search_path` is a session setting so it sticks for as long as the
+        // connection lives in the pool
+        if let Some(schema) = &self.search_path {
+            client
+                .batch_execute(&format!("set search_path to \"{}\"", schema))
+                .await?;
+        }
+
+        if let Some(timeout) = self.statement_timeout {
+            client
+                .batch_execute(&set_statement_timeout(Some(timeout)))
+                .await?;
+        }
+
+        let created = self.stats.created.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::debug!(created, "established database connection");
+
+        Ok(Connection::new(client, self.statement_timeout))
+    }
+
+    // bb8 drops connections that fail either check, so we count them as
+    // discarded here. Churn like this often explains latency spikes, since
+    // the next request has to wait for a new connection.
+
+    // this runs on every checkout, so it also undoes the role the last user
+    // of the connection set. One we can't reset is discarded rather than
+    // handed out with someone else's privileges
+    async fn is_valid(&self, conn: &mut PooledConnection<'_, Self>) -> Result<(), Self::Error> {
+        let query = if conn.role.is_some() {
+            "reset role"
+        } else {
+            ""
+        };
+        let result = conn.simple_query(query).await.map(|_| ());
+        match &result {
+            Ok(()) => conn.role = None,
+            Err(err) => self
+                .stats
+                .record_discard(&format!("failed check on checkout: {}", err)),
+        }
+        result
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        if conn.is_closed() {
+            self.stats.record_discard("closed while checked out");
+            return true;
+        }
+        if conn.in_transaction.load(Ordering::SeqCst) {
+            self.stats.record_discard("left in a transaction");
+            return true;
+        }
+        false
+    }
+}
+
+/// How many connections a [`Manager`] has set up and thrown away again.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    created: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn created(&self) -> u64 {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    pub fn discarded(&self) -> u64 {
+        self.discarded.load(Ordering::Relaxed)
+    }
+
+    fn record_discard(&self, reason: &str) {
+        let discarded = self.discarded.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(%reason, discarded, "discarded broken database connection");
+    }
+}
+
+/// Check that `schema` is allowed to be used as the `search_path`.
+///
+/// It has to be in `allowed` and be a plain identifier, since it ends up
+/// in a `set search_path` statement that cannot take parameters.
+pub fn validate_schema(schema: &str, allowed: &[String]) -> Result<(), String> {
+    validate_identifier("schema", schema, allowed)
+}
+
+/// Check that `role` is allowed to be used with [`Connection::set_role`],
+/// like [`validate_schema`].
+pub fn validate_role(role: &str, allowed: &[String]) -> Result<(), String> {
+    validate_identifier("role", role, allowed)
+}
+
+fn validate_identifier(kind: &str, name: &str, allowed: &[String]) -> Result<(), String> {
+    if !is_identifier(name) {
+        return Err(format!("{:?} is not a valid {} name", name, kind));
+    }
+
+    if !allowed.iter().any(|allowed| allowed == name) {
+        return Err(format!("{} {:?} is not in the allow-list", kind, name));
+    }
+
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    matches!(chars.next(), Some('a'..='z' | '_'))
+        && chars.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_'))
+        && name.len() <= 63
+}
+
+/// Whether `query` has a `limit` clause, roughly.
+fn has_limit(query: &str) -> bool {
+    query
+        .split_whitespace()
+        .any(|word| word.eq_ignore_ascii_case("limit"))
+}
+
+fn set_statement_timeout(timeout: Option<Duration>) -> String {
+    format!("set {}", statement_timeout(timeout))
+}
+
+/// `statement_timeout` is in milliseconds and `0` disables it.
+fn statement_timeout(timeout: Option<Duration>) -> String {
+    let millis = timeout.map_or(0, |timeout| timeout.as_millis());
+    format!("statement_timeout = {}", millis)
+}
+
+/// A pooled database connection.
+///
+/// Derefs to [`Client`] so it can be used like any tokio-postgres client.
+pub struct Connection {
+    client: Client,
+    statements: Mutex<HashMap<String, Statement>>,
+    /// The `statement_timeout` set by [`Manager`], if any.
+    statement_timeout: Option<Duration>,
+    /// Included in a comment on every statement we prepare, see
+    /// [`Connection::set_request_id`].
+    request_id: Option<Uuid>,
+    /// Set while [`Connection::with_transaction`] or
+    /// [`Connection::with_read_tx`] may have a transaction open.
+    in_transaction: AtomicBool,
+    /// The role set with [`Connection::set_role`], if any.
+    role: Option<String>,
+}
+
+impl Connection {
+    fn new(client: Client, statement_timeout: Option<Duration>) -> Self {
+        Self {
+            client,
+            statements: Mutex::new(HashMap::new()),
+            statement_timeout,
+            request_id: None,
+            in_transaction: AtomicBool::new(false),
+            role: None,
+        }
+    }
+
+    /// Run the following statements as `role`, with its privileges rather
+    /// than those of the user we connected as.
+    ///
+    /// The role is reset when the connection is next checked out, or the
+    /// connection is discarded if that fails.
+    ///
+    /// # Panics
+    ///
+    /// If `role` isn't a plain lowercase identifier. Use [`validate_role`] to
+    /// check values coming from configuration.
+    pub async fn set_role(&mut self, role: &str) -> Result<(), Error> {
+        assert!(is_identifier(role), "invalid role name {:?}", role);
+
+        // before running it, so the role is reset even if this fails half way
+        self.role = Some(role.to_string());
+        self.client
+            .batch_execute(&format!("set role \"{}\"", role))
+            .await
+    }
+
+    /// Prefix statements prepared with [`Connection::prepare_cached`] with
+    /// `/* req_id=<id> */`, so queries in `pg_stat_activity` and the server's
+    /// logs can be traced back to the request that ran them.
+    ///
+    /// Those statements can't be cached, since the comment is different for
+    /// every request. The id stays until it is changed, so reset it with
+    /// `None` when checking out a connection for something else.
+    pub fn set_request_id(&mut self, request_id: Option<Uuid>) {
+        self.request_id = request_id;
+    }
+
+    /// Run `query` with `statement_timeout` set to `timeout` rather than the
+    /// pool's default, for the odd query that legitimately takes longer.
+    ///
+    /// The default is restored afterwards, whether or not `query` succeeded.
+    /// If the returned future is dropped before it completes the connection
+    /// keeps `timeout` until it is next used with this method.
+    ///
+    /// Inside [`Connection::with_read_tx`] and the like `timeout` only lasts
+    /// until the transaction ends instead.
+    pub async fn with_statement_timeout<F, T>(
+        &self,
+        timeout: Duration,
+        query: F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        // restoring the default would fail in a transaction `query` aborted,
+        // and lose its error, so leave that to the commit or rollback
+        if self.in_transaction.load(Ordering::SeqCst) {
+            self.client
+                .batch_execute(&format!("set local {}", statement_timeout(Some(timeout))))
+                .await?;
+            return query.await;
+        }
+
+        self.client
+            .batch_execute(&set_statement_timeout(Some(timeout)))
+            .await?;
+
+        let result = query.await;
+
+        self.client
+            .batch_execute(&set_statement_timeout(self.statement_timeout))
+            .await?;
+
+        result
+    }
+
+    /// Run `reads` in a read only transaction, so all of their statements see
+    /// the same snapshot of the database.
+    ///
+    /// The transaction is `repeatable read`, since by default every statement
+    /// gets a new snapshot. It is committed if `reads` succeeded and rolled
+    /// back otherwise. If the returned future is dropped before that the pool
+    /// discards the connection, rather than hand it out in the middle of a
+    /// transaction.
+    pub async fn with_read_tx<F, T, E>(&self, reads: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        self.transaction(
+            "begin transaction isolation level repeatable read read only",
+            true,
+            reads,
+        )
+        .await
+    }
+
+    /// Run the statements of the future `writes` returns in a transaction,
+    /// committing it if they succeeded and `commit` is set, and rolling it
+    /// back otherwise.
+    ///
+    /// If the transaction failed with a serialization failure or deadlock,
+    /// which is expected with concurrent writers, it is run again with a new
+    /// future from `writes`. That happens up to `MAX_TRANSACTION_RETRIES`
+    /// times with a short backoff, before we give up and return the error. So
+    /// `writes` must not have effects outside of the transaction.
+    ///
+    /// Like [`Connection::with_read_tx`] the connection is discarded if the
+    /// returned future is dropped half way.
+    pub async fn with_transaction<F, Fut, T>(
+        &self,
+        commit: bool,
+        mut writes: F,
+    ) -> Result<T, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        let mut retries = 0;
+        loop {
+            match self.transaction("begin", commit, writes()).await {
+                Err(err) if err.is_transaction_conflict() && retries < MAX_TRANSACTION_RETRIES => {
+                    let backoff = TRANSACTION_RETRY_BACKOFF * 2u32.pow(retries);
+                    retries += 1;
+                    tracing::warn!(
+                        err = %err.message(),
+                        retries,
+                        ?backoff,
+                        "transaction conflicted with another one, retrying"
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn transaction<F, T, E>(&self, begin: &str, commit: bool, statements: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        self.in_transaction.store(true, Ordering::SeqCst);
+        self.client.batch_execute(begin).await?;
+
+        let result = statements.await;
+
+        let end = if result.is_ok() && commit {
+            "commit"
+        } else {
+            "rollback"
+        };
+        match self.client.batch_execute(end).await {
+            Ok(()) => {}
+            // Postgres got to the commit, which ends the transaction even if
+            // it fails, say with a serialization failure
+            Err(err) if err.as_db_error().is_some() => {
+                self.in_transaction.store(false, Ordering::SeqCst);
+                return Err(err.into());
+            }
+            Err(err) => return Err(err.into()),
+        }
+        self.in_transaction.store(false, Ordering::SeqCst);
+
+        result
+    }
+
+    /// Prepare `query`, reusing the statement if it has already been prepared
+    /// on this connection.
+    ///
+    /// Passing a `&str` to `query` and friends prepares the statement every
+    /// time, costing an extra round trip and having Postgres parse and plan it
+    /// again. Locally that made a lookup by primary key take ~50µs rather than
+    /// ~16µs with a cached statement.
+    ///
+    /// Prepared statements only exist on the connection that prepared them,
+    /// which is why the cache lives here and not in the pool.
+    pub async fn prepare_cached(&self, query: &str) -> Result<Statement, Error> {
+        if let Some(request_id) = self.request_id {
+            return self
+                .client
+                .prepare(&format!("/* req_id={} */ {}", request_id, query))
+                .await;
+        }
+
+        if let Some(statement) = self.statements.lock().unwrap().get(query) {
+            return Ok(statement.clone());
+        }
+
+        let statement = self.client.prepare(query).await?;
+        self.statements
+            .lock()
+            .unwrap()
+            .insert(query.to_string(), statement.clone());
+
+        Ok(statement)
+    }
+
+    /// Run `query`, which may return many rows but must have a `limit`.
+    ///
+    /// Every query returning many rows goes through here, so one that could
+    /// read a whole table into memory trips an assertion in debug builds, and
+    /// so in tests.
+    pub async fn query_limited(
+        &self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, Error> {
+        debug_assert!(has_limit(query), "query has no limit: {}", query);
+        let statement = self.prepare_cached(query).await?;
+        self.client.query(&statement, params).await
+    }
+
+    /// The number of statements prepared on this connection.
+    #[cfg(test)]
+    pub fn cached_statements(&self) -> usize {
+        self.statements.lock().unwrap().len()
+    }
+}
+
+impl Deref for Connection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+/// The raw bytes of a text column.
+///
+/// Reading text as `String` fails if it isn't valid UTF-8, which can happen
+/// if it was written by another system into a database that doesn't check
+/// encodings. This lets us get at the bytes anyway.
+pub struct RawText<'a>(pub &'a [u8]);
+
+impl<'a> FromSql<'a> for RawText<'a> {
+    fn from_sql(
+        _ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self(raw))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(
+            *ty,
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::UNKNOWN | Type::BYTEA
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_helpers::{test_db, CapturedLogs},
+        Config,
+    };
+    use tokio_postgres::error::SqlState;
+
+    #[test]
+    fn queries_without_limit_are_caught() {
+        assert!(has_limit("select id from users order by id LIMIT $1"));
+        assert!(!has_limit("select id from users order by id"));
+        assert!(!has_limit("select unlimited from users"));
+    }
+
+    #[test]
+    fn schema_must_be_allowed_identifier() {
+        let allowed = vec!["public".to_string(), "tenant_1".to_string()];
+
+        assert!(validate_schema("tenant_1", &allowed).is_ok());
+        assert!(validate_schema("tenant_2", &allowed).is_err());
+        assert!(validate_schema("public; drop table users", &allowed).is_err());
+        assert!(validate_schema("Public", &["Public".to_string()]).is_err());
+    }
+
+    #[test]
+    fn roles_must_be_allowed_identifiers() {
+        let allowed = vec!["app_reader".to_string()];
+
+        assert!(validate_role("app_reader", &allowed).is_ok());
+        assert_eq!(
+            validate_role("postgres", &allowed).unwrap_err(),
+            "role \"postgres\" is not in the allow-list"
+        );
+        assert!(validate_role("app_reader\"; reset role; --", &allowed).is_err());
+    }
+
+    #[tokio::test]
+    async fn queries_resolve_against_configured_schema() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .execute("insert into users (name, age) values ('alice', 30)", &[])
+            .await
+            .unwrap();
+
+        // the test database uses `Manager::search_path` to point at its own
+        // schema, so the unqualified `users` must be the one we just inserted into
+        let conn = db.pool.get().await.unwrap();
+        let row = conn
+            .query_one("select current_schema(), count(*) from users", &[])
+            .await
+            .unwrap();
+
+        assert_eq!(row.get::<_, String>(0), db.schema());
+        assert_eq!(row.get::<_, i64>(1), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_in_a_transaction_see_one_snapshot() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let conn = db.pool.get().await.unwrap();
+        let other = db.client().await;
+        let count = || async {
+            conn.query_one("select count(*) from users", &[])
+                .await
+                .map(|row| row.get::<_, i64>(0))
+        };
+
+        let (before, after) = conn
+            .with_read_tx(async {
+                let before = count().await?;
+                other
+                    .execute("insert into users (name, age) values ('alice', 30)", &[])
+                    .await?;
+                Ok::<_, Error>((before, count().await?))
+            })
+            .await
+            .unwrap();
+        assert_eq!((before, after), (0, 0));
+        assert_eq!(count().await.unwrap(), 1);
+
+        let err = conn
+            .with_read_tx(conn.execute("delete from users", &[]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(&SqlState::READ_ONLY_SQL_TRANSACTION));
+        // rolled back, so the connection is usable again
+        assert_eq!(count().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn transactions_are_retried_after_conflicts() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let conn = db.pool.get().await.unwrap();
+        let conn = &*conn;
+        let fail_with = |errcode: &str| {
+            format!(
+                "do $$ begin raise exception 'conflict' using errcode = '{}'; end $$",
+                errcode
+            )
+        };
+        let attempts = AtomicU64::new(0);
+        let attempts = &attempts;
+
+        // the first attempt fails with a serialization failure and is rolled
+        // back, the second goes through
+        let serialization_failure = fail_with("serialization_failure");
+        let serialization_failure = &serialization_failure;
+        let inserted = conn
+            .with_transaction(true, || async move {
+                conn.execute("insert into users (name, age) values ('alice', 30)", &[])
+                    .await?;
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    conn.batch_execute(serialization_failure).await?;
+                }
+                Ok(())
+            })
+            .await;
+        assert!(inserted.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let row = conn
+            .query_one("select count(*) from users", &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, i64>(0), 1);
+
+        // until we run out of retries
+        attempts.store(0, Ordering::SeqCst);
+        let deadlock = fail_with("deadlock_detected");
+        let deadlock = &deadlock;
+        let err = conn
+            .with_transaction(true, || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Ok(conn.batch_execute(deadlock).await?)
+            })
+            .await
+            .unwrap_err();
+        assert!(err.is_transaction_conflict(), "{}", err.message());
+        assert_eq!(
+            attempts.load(Ordering::SeqCst),
+            u64::from(MAX_TRANSACTION_RETRIES) + 1
+        );
+
+        // other errors aren't retried
+        attempts.store(0, Ordering::SeqCst);
+        let err = conn
+            .with_transaction(true, || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Ok(conn.batch_execute("select * from missing").await?)
+            })
+            .await
+            .unwrap_err();
+        assert!(!err.is_transaction_conflict());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn connections_left_in_a_transaction_are_discarded() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let state = db.state_with(Config::from_env().unwrap()).await;
+        let conn = state.pool().unwrap().get_owned().await.unwrap();
+
+        let reads = conn.with_read_tx(std::future::pending::<Result<(), Error>>());
+        assert!(tokio::time::timeout(Duration::from_millis(50), reads)
+            .await
+            .is_err());
+        drop(conn);
+
+        assert_eq!(state.connection_stats.discarded(), 1);
+    }
+
+    #[tokio::test]
+    async fn statements_are_tagged_with_request_id() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let mut conn = db.pool.get().await.unwrap();
+        let request_id = Uuid::new_v4();
+        conn.set_request_id(Some(request_id));
+
+        // the text of the statement that is running, which is this one
+        let statement = conn
+            .prepare_cached("select query from pg_stat_activity where pid = pg_backend_pid()")
+            .await
+            .unwrap();
+        let query: String = conn.query_one(&statement, &[]).await.unwrap().get(0);
+
+        assert!(
+            query.starts_with(&format!("/* req_id={} */ select", request_id)),
+            "{}",
+            query
+        );
+        assert_eq!(conn.cached_statements(), 0);
+    }
+
+    #[tokio::test]
+    async fn statements_are_cached_per_connection() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        let first = db.pool.get().await.unwrap();
+        let second = db.pool.get().await.unwrap();
+
+        first.prepare_cached("select 1").await.unwrap();
+        first.prepare_cached("select 1").await.unwrap();
+        assert_eq!(first.cached_statements(), 1);
+
+        // the other connection has to prepare it itself
+        assert_eq!(second.cached_statements(), 0);
+        let statement = second.prepare_cached("select 1").await.unwrap();
+        second.query_one(&statement, &[]).await.unwrap();
+        assert_eq!(second.cached_statements(), 1);
+    }
+
+    #[tokio::test]
+    async fn statement_timeout_can_be_extended_for_one_query() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let config = Config {
+            statement_timeout: Duration::from_millis(50),
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+        let conn = state.pool().unwrap().get_owned().await.unwrap();
+
+        let slow_query = || conn.simple_query("select pg_sleep(0.2)");
+
+        let err = slow_query().await.unwrap_err();
+        assert_eq!(err.code(), Some(&SqlState::QUERY_CANCELED));
+
+        conn.with_statement_timeout(Duration::from_secs(5), slow_query())
+            .await
+            .unwrap();
+
+        // and the default is back
+        let err = slow_query().await.unwrap_err();
+        assert_eq!(err.code(), Some(&SqlState::QUERY_CANCELED));
+    }
+
+    #[tokio::test]
+    async fn statement_timeouts_in_a_transaction_keep_the_query_error() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let config = Config {
+            statement_timeout: Duration::from_millis(50),
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+        let conn = state.pool().unwrap().get_owned().await.unwrap();
+
+        let slow_query = || conn.simple_query("select pg_sleep(0.2)");
+
+        let reads = conn.with_statement_timeout(Duration::from_millis(100), slow_query());
+        let err = conn.with_read_tx(reads).await.unwrap_err();
+        assert_eq!(err.code(), Some(&SqlState::QUERY_CANCELED));
+
+        let reads = conn.with_statement_timeout(Duration::from_secs(5), slow_query());
+        conn.with_read_tx(reads).await.unwrap();
+
+        // only for as long as the transaction
+        let err = slow_query().await.unwrap_err();
+        assert_eq!(err.code(), Some(&SqlState::QUERY_CANCELED));
+    }
+
+    #[tokio::test]
+    async fn broken_connections_are_discarded_on_next_use() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let config = Config {
+            pool_max_size: 1,
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+        let pool = state.pool().unwrap();
+
+        let pid: i32 = pool
+            .get()
+            .await
+            .unwrap()
+            .query_one("select pg_backend_pid()", &[])
+            .await
+            .unwrap()
+            .get(0);
+        db.client()
+            .await
+            .execute("select pg_terminate_backend($1)", &[&pid])
+            .await
+            .unwrap();
+
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+
+        // we get a new connection in place of the broken one
+        let conn = pool.get().await.unwrap();
+        conn.simple_query("select 1").await.unwrap();
+
+        let logs = logs.contents();
+        assert!(
+            logs.contains("discarded broken database connection"),
+            "{}",
+            logs
+        );
+        assert!(logs.contains("established database connection"), "{}", logs);
+        assert_eq!(state.connection_stats.discarded(), 1);
+        assert_eq!(state.connection_stats.created(), 2);
+    }
+}
@@ -0,0 +1,99 @@
+//! When a request runs out of time, so we can avoid starting work it won't be
+//! around to see finish, and cutting it off once it has.
+
+use axum::http::Request;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{timeout::error::Elapsed, BoxError, Service};
+
+/// The latest a request can finish before `REQUEST_TIMEOUT_SECS`, or the
+/// timeout of its route, cuts it off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Attach a [`Deadline`] `timeout` from now to the request.
+pub fn assign<B>(mut request: Request<B>, timeout: Duration) -> Request<B> {
+    request
+        .extensions_mut()
+        .insert(Deadline(Instant::now() + timeout));
+    request
+}
+
+/// Like [`tower::timeout::Timeout`], but failing requests with
+/// [`Elapsed`] at their [`Deadline`] so routes can have timeouts of their
+/// own. Requests without one get `timeout` from when they arrive.
+#[derive(Clone)]
+pub struct Timeout<S> {
+    inner: S,
+    timeout: Duration,
+}
+
+impl<S> Timeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<S, B> Service<Request<B>> for Timeout<S>
+where
+    S: Service<Request<B>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let deadline = req
+            .extensions()
+            .get::<Deadline>()
+            .map_or_else(|| Instant::now() + self.timeout, |deadline| deadline.0);
+
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            match tokio::time::timeout_at(deadline.into(), future).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => Err(Elapsed::new().into()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn requests_are_cut_off_at_their_deadline() {
+        let slow = tower::service_fn(|_: Request<()>| async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok::<_, Infallible>(())
+        });
+        let timeout = Timeout::new(slow, Duration::from_secs(5));
+
+        let request = assign(Request::new(()), Duration::from_millis(50));
+        let err = timeout.clone().oneshot(request).await.unwrap_err();
+        assert!(err.is::<Elapsed>());
+
+        // otherwise they get the default
+        timeout.oneshot(Request::new(())).await.unwrap();
+    }
+}
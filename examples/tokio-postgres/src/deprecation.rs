@@ -0,0 +1,102 @@
+//! Warning clients off deprecated routes before they are removed.
+//!
+//! Responses from routes with a `sunset` in [`ROUTES`](crate::ROUTES) get
+//! `Deprecation: true` and `Sunset: <date>` headers, as in the IETF
+//! deprecation and sunset drafts. Every use is logged too, so we can tell
+//! who still has to move before the route goes away.
+
+use crate::route_for;
+use axum::{
+    body::BoxBody,
+    http::{HeaderValue, Request, Response},
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::Service;
+
+/// Middleware adding the deprecation headers, see the [module docs](self).
+#[derive(Clone)]
+pub struct Deprecations<S> {
+    inner: S,
+}
+
+impl<S> Deprecations<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, B> Service<Request<B>> for Deprecations<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let deprecated = route_for(req.uri().path())
+            .and_then(|route| route.sunset.map(|sunset| (route.path, sunset)));
+        let (route, sunset) = match deprecated {
+            Some(deprecated) => deprecated,
+            None => return Box::pin(self.inner.call(req)),
+        };
+
+        tracing::warn!(route, sunset, method = %req.method(), "deprecated route used");
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            let headers = response.headers_mut();
+            headers.insert("deprecation", HeaderValue::from_static("true"));
+            headers.insert("sunset", HeaderValue::from_static(sunset));
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app, test_helpers::mock_state};
+    use axum::{
+        body::Body,
+        http::{Method, Request},
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn deprecated_routes_say_so() {
+        let state = mock_state();
+
+        let response = app(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["deprecation"], "true");
+        assert_eq!(
+            response.headers()["sunset"],
+            "Wed, 30 Jun 2027 00:00:00 GMT"
+        );
+
+        let response = app(state)
+            .oneshot(Request::get("/users").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(response.headers().get("deprecation").is_none());
+        assert!(response.headers().get("sunset").is_none());
+    }
+}
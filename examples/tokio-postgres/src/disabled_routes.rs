@@ -0,0 +1,196 @@
+//! A kill switch for routes, so operators can turn off one that is causing
+//! trouble without redeploying.
+//!
+//! Routes are named by their path in [`ROUTES`], like `/users/import`. They
+//! are disabled at startup with `DISABLED_ROUTES`, a comma separated list, and
+//! at runtime with `PUT /admin/disabled-routes`.
+
+use crate::{error::AppError, route_for, ROUTES};
+use axum::{
+    body::{box_body, BoxBody},
+    http::{Request, Response, StatusCode},
+    response::IntoResponse,
+};
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+use tower::Service;
+
+/// The route that turns routes back on, which can't be disabled itself.
+const ADMIN_ROUTE: &str = "/admin/disabled-routes";
+
+/// The set of disabled routes, shared by [`DisableRoutes`] and the admin
+/// endpoint that changes it.
+#[derive(Debug, Default)]
+pub struct DisabledRoutes {
+    routes: RwLock<BTreeSet<&'static str>>,
+}
+
+impl DisabledRoutes {
+    /// Disable exactly `routes`, turning any others back on.
+    ///
+    /// Fails without changing anything if one of them isn't a route.
+    pub fn set(&self, routes: &[String]) -> Result<(), String> {
+        let routes = parse(routes)?;
+        *self.routes.write().unwrap() = routes;
+        Ok(())
+    }
+
+    /// The disabled routes, in order.
+    pub fn list(&self) -> Vec<&'static str> {
+        self.routes.read().unwrap().iter().copied().collect()
+    }
+
+    fn is_disabled(&self, path: &str) -> bool {
+        let routes = self.routes.read().unwrap();
+        !routes.is_empty() && route_for(path).map_or(false, |route| routes.contains(route.path))
+    }
+}
+
+/// Check that `routes` can be disabled, for `DISABLED_ROUTES`.
+pub fn validate(routes: &[String]) -> Result<(), String> {
+    parse(routes).map(|_| ())
+}
+
+fn parse(routes: &[String]) -> Result<BTreeSet<&'static str>, String> {
+    routes
+        .iter()
+        .map(|name| {
+            let route = ROUTES
+                .iter()
+                .find(|route| route.path == name)
+                .ok_or_else(|| format!("there is no route {:?}", name))?;
+            if route.path == ADMIN_ROUTE {
+                return Err(format!("{} can't be disabled", ADMIN_ROUTE));
+            }
+            Ok(route.path)
+        })
+        .collect()
+}
+
+/// Middleware that responds to requests for disabled routes with `503
+/// Service Unavailable`, before they wait for a concurrency slot.
+#[derive(Clone)]
+pub struct DisableRoutes<S> {
+    inner: S,
+    disabled: Arc<DisabledRoutes>,
+}
+
+impl<S> DisableRoutes<S> {
+    pub fn new(inner: S, disabled: Arc<DisabledRoutes>) -> Self {
+        Self { inner, disabled }
+    }
+}
+
+impl<S, B> Service<Request<B>> for DisableRoutes<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        if self.disabled.is_disabled(req.uri().path()) {
+            let response = AppError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "route is temporarily disabled",
+            )
+            .into_response()
+            .map(box_body);
+            return Box::pin(async move { Ok(response) });
+        }
+
+        Box::pin(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, repository::MockUsers, test_helpers::send, AppState, Config};
+    use axum::{
+        body::Body,
+        http::{header, Method},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    #[test]
+    fn only_existing_routes_can_be_disabled() {
+        assert!(validate(&["/users/import".to_string()]).is_ok());
+        assert_eq!(
+            validate(&["/users/export".to_string()]).unwrap_err(),
+            "there is no route \"/users/export\""
+        );
+        assert!(validate(&[ADMIN_ROUTE.to_string()]).is_err());
+    }
+
+    async fn put_disabled(state: &crate::SharedState, routes: Value) -> (StatusCode, Value) {
+        let response = app(state.clone())
+            .oneshot(
+                Request::put(ADMIN_ROUTE)
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(json!({ "routes": routes }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn disabled_routes_are_unavailable_until_enabled_again() {
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            disabled_routes: vec!["/users/by-name/:name".to_string()],
+            ..Config::from_env().unwrap()
+        };
+        let state = Arc::new(AppState::mock(config, MockUsers::default()));
+
+        let (status, _) = send(
+            app(state.clone()),
+            Method::GET,
+            "/users/by-name/alice",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let (status, body) = put_disabled(&state, json!(["/users/:id/similar"])).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "routes": ["/users/:id/similar"] }));
+
+        let (status, _) = send(app(state.clone()), Method::GET, "/users/1/similar", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        // the others keep working, including the one enabled again
+        let (status, _) = send(
+            app(state.clone()),
+            Method::GET,
+            "/users/by-name/alice",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(app(state.clone()), Method::GET, "/users", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // unknown routes are rejected, leaving the set alone
+        let (status, _) = put_disabled(&state, json!(["/users/export"])).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(state.disabled_routes.list(), vec!["/users/:id/similar"]);
+    }
+}
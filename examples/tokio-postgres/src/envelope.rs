@@ -0,0 +1,65 @@
+//! Optionally wraps JSON responses as `{"data": ...}`, for clients that
+//! prefer every payload to have the same shape.
+
+use axum::{
+    body::{box_body, BoxBody, Bytes, Full},
+    http::{header, HeaderValue, Response, StatusCode},
+};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+
+/// Wrap the body of `response` in an envelope if `enabled` is true.
+///
+/// Only JSON responses that aren't errors are wrapped. Errors are left as
+/// they are.
+pub async fn wrap(
+    response: Response<BoxBody>,
+    enabled: bool,
+) -> Result<Response<BoxBody>, Infallible> {
+    if !enabled || !should_wrap(&response) {
+        return Ok(response);
+    }
+
+    Ok(map_json_body(response, |data| json!({ "data": data })).await)
+}
+
+/// Replace the JSON body of `response` with what `f` makes of it.
+///
+/// Middleware rewriting JSON responses, like this one and
+/// [`naming::rename`](crate::naming::rename), go through here. Bodies that
+/// turn out not to be JSON are left alone.
+pub async fn map_json_body<F>(response: Response<BoxBody>, f: F) -> Response<BoxBody>
+where
+    F: FnOnce(Value) -> Value,
+{
+    let (mut parts, body) = response.into_parts();
+
+    let data = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            tracing::error!(%err, "failed to read response body");
+            let mut response = Response::new(box_body(Full::from("failed to read response")));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return response;
+        }
+    };
+
+    let body = match serde_json::from_slice::<Value>(&data) {
+        Ok(data) => Bytes::from(f(data).to_string()),
+        // not actually JSON so leave it alone
+        Err(_) => data,
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, box_body(Full::from(body)))
+}
+
+fn should_wrap<B>(response: &Response<B>) -> bool {
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        return false;
+    }
+
+    response.headers().get(header::CONTENT_TYPE)
+        == Some(&HeaderValue::from_static("application/json"))
+}
@@ -0,0 +1,310 @@
+//! The error type returned by our handlers and extractors.
+
+use axum::{
+    body::{Bytes, Full},
+    http::{header, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+};
+use std::{convert::Infallible, error::Error as _, io, time::Duration};
+use tokio_postgres::error::SqlState;
+
+/// Used for `Retry-After` on throttling responses that don't say how long
+/// clients should wait.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// The message of an [`AppError`], stored in its response's extensions so
+/// middleware can render it differently.
+#[derive(Debug, Clone)]
+pub struct ErrorMessage(pub String);
+
+/// An error response with a plain text message.
+#[derive(Debug)]
+pub struct AppError {
+    status: StatusCode,
+    message: String,
+    retry_after: Option<Duration>,
+    /// Set if we lost the connection to the database, see
+    /// [`AppError::is_connection_lost`].
+    connection_lost: bool,
+    /// Set if Postgres aborted a transaction for conflicting with another
+    /// one, see [`AppError::is_transaction_conflict`].
+    transaction_conflict: bool,
+}
+
+impl AppError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            retry_after: None,
+            connection_lost: false,
+            transaction_conflict: false,
+        }
+    }
+
+    /// Map any error into a `500 Internal Server Error`.
+    pub fn internal<E>(err: E) -> Self
+    where
+        E: std::error::Error,
+    {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    }
+
+    /// A `503 Service Unavailable` telling clients to retry after
+    /// `retry_after`.
+    pub fn unavailable(message: impl Into<String>, retry_after: Duration) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, message).retry_after(retry_after)
+    }
+
+    /// A `500 Internal Server Error` for losing the connection to the
+    /// database, see [`AppError::is_connection_lost`].
+    pub fn connection_lost(message: impl Into<String>) -> Self {
+        Self {
+            connection_lost: true,
+            ..Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+        }
+    }
+
+    /// A `503 Service Unavailable` for a transaction Postgres aborted, see
+    /// [`AppError::is_transaction_conflict`].
+    pub fn transaction_conflict(message: impl Into<String>) -> Self {
+        Self {
+            transaction_conflict: true,
+            ..Self::new(StatusCode::SERVICE_UNAVAILABLE, message)
+        }
+    }
+
+    /// A `429 Too Many Requests` telling clients to retry after
+    /// `retry_after`.
+    pub fn too_many_requests(message: impl Into<String>, retry_after: Duration) -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS, message).retry_after(retry_after)
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Whether this happened because the connection to the database was lost.
+    ///
+    /// Since the connection can go away after Postgres committed a write but
+    /// before we heard back, we can't tell whether a write that failed like
+    /// this went through. It must only be retried if doing it twice is
+    /// harmless.
+    pub fn is_connection_lost(&self) -> bool {
+        self.connection_lost
+    }
+
+    /// Whether Postgres aborted a transaction because it conflicted with a
+    /// concurrent one, with a serialization failure or a deadlock.
+    ///
+    /// Nothing the transaction did took effect, so running it again is safe
+    /// and will usually succeed.
+    /// [`Connection::with_transaction`](crate::db::Connection::with_transaction)
+    /// does that, so this only reaches clients once it gave up.
+    pub fn is_transaction_conflict(&self) -> bool {
+        self.transaction_conflict
+    }
+}
+
+impl From<tokio_postgres::Error> for AppError {
+    /// Constraint violations mean the client sent data we can't store, so
+    /// they get a `422 Unprocessable Entity`. Transactions conflicting with
+    /// others get a `503 Service Unavailable`, since retrying later will
+    /// likely work. Anything else is our fault.
+    fn from(err: tokio_postgres::Error) -> Self {
+        if connection_lost(&err) {
+            return Self::connection_lost(err.to_string());
+        }
+
+        if err.code() == Some(&SqlState::T_R_SERIALIZATION_FAILURE)
+            || err.code() == Some(&SqlState::T_R_DEADLOCK_DETECTED)
+        {
+            return Self::transaction_conflict(err.to_string());
+        }
+
+        let db_error = match err.as_db_error() {
+            Some(db_error) => db_error,
+            None => return Self::internal(err),
+        };
+
+        let message = match db_error.code() {
+            code if *code == SqlState::CHECK_VIOLATION => format!(
+                "violates check constraint `{}`",
+                db_error.constraint().unwrap_or("unknown")
+            ),
+            code if *code == SqlState::NOT_NULL_VIOLATION => format!(
+                "`{}` must not be null",
+                db_error.column().unwrap_or("unknown")
+            ),
+            _ => return Self::internal(err),
+        };
+
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, message)
+    }
+}
+
+/// Whether `err` means the connection is gone, rather than that a statement
+/// failed.
+fn connection_lost(err: &tokio_postgres::Error) -> bool {
+    if err.is_closed() {
+        return true;
+    }
+
+    match err.code() {
+        // connection exceptions, or the server shutting down or killing our
+        // backend
+        Some(code) => {
+            code.code().starts_with("08")
+                || *code == SqlState::ADMIN_SHUTDOWN
+                || *code == SqlState::CRASH_SHUTDOWN
+                || *code == SqlState::CANNOT_CONNECT_NOW
+        }
+        None => err
+            .source()
+            .map_or(false, |source| source.is::<io::Error>()),
+    }
+}
+
+/// Whether clients should back off and retry requests that got `status`.
+fn is_throttling(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+impl IntoResponse for AppError {
+    type Body = Full<Bytes>;
+    type BodyError = Infallible;
+
+    /// Internal errors are logged with their message here, since depending
+    /// on [`ErrorDetail`](crate::error_detail::ErrorDetail) clients may not
+    /// get to see it.
+    fn into_response(self) -> Response<Self::Body> {
+        if self.status == StatusCode::INTERNAL_SERVER_ERROR {
+            tracing::error!(error = %self.message, "internal server error");
+        }
+
+        let mut response = (self.status, self.message.clone()).into_response();
+        response.extensions_mut().insert(ErrorMessage(self.message));
+
+        if is_throttling(self.status) {
+            let retry_after = self.retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after_value(retry_after));
+        }
+
+        response
+    }
+}
+
+/// `Retry-After` is in whole seconds, so round up and never tell clients to
+/// retry immediately.
+fn retry_after_value(retry_after: Duration) -> HeaderValue {
+    let mut secs = retry_after.as_secs();
+    if retry_after.subsec_nanos() > 0 {
+        secs += 1;
+    }
+    HeaderValue::from(secs.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_db;
+
+    fn retry_after_header(error: AppError) -> Option<u64> {
+        error
+            .into_response()
+            .headers()
+            .get(header::RETRY_AFTER)
+            .map(|value| value.to_str().unwrap().parse().unwrap())
+    }
+
+    #[test]
+    fn throttling_responses_have_retry_after() {
+        assert_eq!(
+            retry_after_header(AppError::too_many_requests(
+                "slow down",
+                Duration::from_secs(30)
+            )),
+            Some(30)
+        );
+        assert_eq!(
+            retry_after_header(AppError::unavailable(
+                "try again",
+                Duration::from_millis(1500)
+            )),
+            Some(2)
+        );
+        assert_eq!(
+            retry_after_header(AppError::new(StatusCode::SERVICE_UNAVAILABLE, "down")),
+            Some(1)
+        );
+        assert_eq!(
+            retry_after_header(AppError::new(StatusCode::NOT_FOUND, "not found")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn constraint_violations_are_unprocessable() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let client = db.client().await;
+
+        for (query, expected) in vec![
+            (
+                "insert into users (name, age) values ('alice', -1)",
+                "violates check constraint `users_age_check`",
+            ),
+            (
+                "insert into users (name, age) values (null, 30)",
+                "`name` must not be null",
+            ),
+        ] {
+            let err = AppError::from(client.execute(query, &[]).await.unwrap_err());
+
+            assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY, "{}", query);
+            assert_eq!(err.message, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn lost_connections_are_detected() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let client = db.client().await;
+        let pid: i32 = client
+            .query_one("select pg_backend_pid()", &[])
+            .await
+            .unwrap()
+            .get(0);
+
+        db.client()
+            .await
+            .execute("select pg_terminate_backend($1)", &[&pid])
+            .await
+            .unwrap();
+
+        let err = AppError::from(client.execute("select 1", &[]).await.unwrap_err());
+        assert!(err.is_connection_lost(), "{}", err.message);
+
+        // statements failing isn't losing the connection
+        let err = AppError::from(
+            db.client()
+                .await
+                .execute("select * from missing", &[])
+                .await
+                .unwrap_err(),
+        );
+        assert!(!err.is_connection_lost());
+    }
+}
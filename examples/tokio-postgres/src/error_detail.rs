@@ -0,0 +1,128 @@
+//! How much of what went wrong `500 Internal Server Error` responses tell
+//! clients.
+//!
+//! Their messages come straight from whatever failed, like a Postgres error
+//! naming tables and columns, which is handy in development but not something
+//! to show the world. With `ERROR_DETAIL=safe`, the default, clients get a
+//! generic message instead. The full one is always logged by
+//! [`AppError`](crate::error::AppError).
+
+use crate::error::ErrorMessage;
+use axum::{
+    body::{box_body, BoxBody, Full},
+    http::{header, Response, StatusCode},
+};
+use std::str::FromStr;
+
+/// What clients get instead of the message of an internal error.
+const GENERIC_MESSAGE: &str = "internal server error";
+
+/// The `ERROR_DETAIL` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorDetail {
+    /// Send the message of internal errors to clients.
+    Full,
+    /// Send a generic message instead.
+    Safe,
+}
+
+impl ErrorDetail {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Safe => "safe",
+        }
+    }
+}
+
+impl FromStr for ErrorDetail {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "safe" => Ok(Self::Safe),
+            _ => Err(format!("expected `full` or `safe`, got {:?}", s)),
+        }
+    }
+}
+
+/// Replace the message of `response` if it is an internal error and `detail`
+/// is [`ErrorDetail::Safe`].
+///
+/// This runs before [`ProblemJson`](crate::problem::ProblemJson), so problem
+/// details get the generic message too.
+pub fn redact(mut response: Response<BoxBody>, detail: ErrorDetail) -> Response<BoxBody> {
+    if detail == ErrorDetail::Full || response.status() != StatusCode::INTERNAL_SERVER_ERROR {
+        return response;
+    }
+
+    response
+        .extensions_mut()
+        .insert(ErrorMessage(GENERIC_MESSAGE.to_string()));
+    response.headers_mut().remove(header::CONTENT_LENGTH);
+    *response.body_mut() = box_body(Full::from(GENERIC_MESSAGE));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, repository::MockUsers, test_helpers::CapturedLogs, AppState, Config};
+    use axum::{body::Body, http::Request};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[test]
+    fn detail_levels_are_parsed() {
+        assert_eq!("full".parse(), Ok(ErrorDetail::Full));
+        assert_eq!("safe".parse(), Ok(ErrorDetail::Safe));
+        assert!("verbose".parse::<ErrorDetail>().is_err());
+    }
+
+    #[tokio::test]
+    async fn internal_errors_are_only_detailed_in_full_mode() {
+        for (error_detail, accept, expected) in vec![
+            (ErrorDetail::Full, "text/plain", "connection closed"),
+            (ErrorDetail::Safe, "text/plain", GENERIC_MESSAGE),
+            (
+                ErrorDetail::Safe,
+                "application/problem+json",
+                r#"{"detail":"internal server error","status":500,"title":"Internal Server Error","type":"about:blank"}"#,
+            ),
+        ] {
+            let config = Config {
+                error_detail,
+                ..Config::from_env().unwrap()
+            };
+            let users = MockUsers::default();
+            users.lose_connection_after_next_create();
+            let state = Arc::new(AppState::mock(config, users));
+
+            let logs = CapturedLogs::default();
+            let _guard = tracing::subscriber::set_default(logs.subscriber());
+
+            let response = app(state)
+                .oneshot(
+                    Request::post("/users")
+                        .header(header::ACCEPT, accept)
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(r#"{"name":"alice","age":30}"#))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, expected, "{:?}", error_detail);
+
+            // whatever clients are told, we know what happened
+            let logs = logs.contents();
+            assert!(
+                logs.contains("internal server error error=connection closed"),
+                "{}",
+                logs
+            );
+        }
+    }
+}
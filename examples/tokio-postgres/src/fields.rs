@@ -0,0 +1,190 @@
+//! Picking which fields of a user to return, with `?fields=`.
+
+use crate::{error::AppError, read_age, read_name, user_id::UserId, User};
+use axum::http::StatusCode;
+use serde::Serialize;
+use tokio_postgres::Row;
+
+/// A field of a [`User`], and the column it is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserField {
+    Id,
+    Name,
+    Age,
+}
+
+impl UserField {
+    pub const ALL: &'static [Self] = &[Self::Id, Self::Name, Self::Age];
+
+    /// The column to select for this field, which is also its name.
+    ///
+    /// Only these ever end up in a query, never what the client sent.
+    pub fn column(self) -> &'static str {
+        match self {
+            Self::Id => "id",
+            Self::Name => "name",
+            Self::Age => "age",
+        }
+    }
+
+    /// Like [`UserField::column`] but reading `name` as raw bytes.
+    ///
+    /// Postgres refuses to send text that isn't valid UTF-8 to us, since
+    /// tokio-postgres always asks for UTF-8. Converting to `SQL_ASCII` skips
+    /// that check and gives us the raw bytes, as `bytea`, instead.
+    pub fn raw_column(self) -> &'static str {
+        match self {
+            Self::Name => "convert_to(name, 'SQL_ASCII') as name",
+            field => field.column(),
+        }
+    }
+
+    /// Parse a comma separated list of field names, like `id,name`.
+    ///
+    /// The fields are returned in the order of [`UserField::ALL`] without
+    /// duplicates, so the same selection always results in the same query.
+    pub fn parse_list(list: &str) -> Result<Vec<Self>, AppError> {
+        let mut fields = Vec::new();
+        for name in list.split(',').map(str::trim) {
+            let field = Self::ALL
+                .iter()
+                .copied()
+                .find(|field| field.column() == name)
+                .ok_or_else(|| {
+                    let expected = Self::ALL
+                        .iter()
+                        .map(|field| field.column())
+                        .collect::<Vec<_>>();
+                    AppError::new(
+                        StatusCode::BAD_REQUEST,
+                        format!(
+                            "unknown field `{}`, expected one of {}",
+                            name,
+                            expected.join(", ")
+                        ),
+                    )
+                })?;
+            fields.push(field);
+        }
+
+        Ok(Self::ALL
+            .iter()
+            .copied()
+            .filter(|field| fields.contains(field))
+            .collect())
+    }
+}
+
+/// The columns to select for `fields`, separated by commas.
+pub fn columns(fields: &[UserField], column: fn(UserField) -> &'static str) -> String {
+    fields
+        .iter()
+        .map(|field| column(*field))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Some of the fields of a [`User`], the rest are left out when serialized.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PartialUser {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<i64>,
+}
+
+impl PartialUser {
+    /// Keep only `fields` of `user`.
+    pub fn from_user(user: User, fields: &[UserField]) -> Self {
+        Self {
+            id: Some(user.id).filter(|_| fields.contains(&UserField::Id)),
+            name: Some(user.name).filter(|_| fields.contains(&UserField::Name)),
+            age: Some(user.age).filter(|_| fields.contains(&UserField::Age)),
+        }
+    }
+
+    /// Read `fields` from a row with just their columns.
+    pub fn from_row(row: &Row, fields: &[UserField]) -> Result<Self, AppError> {
+        let mut user = Self::default();
+        for field in fields {
+            match field {
+                UserField::Id => user.id = Some(row.try_get("id")?),
+                UserField::Name => user.name = Some(read_name(row)?),
+                UserField::Age => user.age = Some(read_age(row)?),
+            }
+        }
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app,
+        test_helpers::{mock_state, send, test_db},
+    };
+    use axum::http::Method;
+    use serde_json::json;
+
+    #[test]
+    fn fields_are_parsed_against_allow_list() {
+        assert_eq!(
+            UserField::parse_list("name, id,name").unwrap(),
+            vec![UserField::Id, UserField::Name]
+        );
+        assert_eq!(
+            UserField::parse_list("id,password").unwrap_err().message(),
+            "unknown field `password`, expected one of id, name, age"
+        );
+        assert!(UserField::parse_list("").is_err());
+    }
+
+    #[tokio::test]
+    async fn only_requested_fields_are_returned() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .execute("insert into users (name, age) values ('alice', 30)", &[])
+            .await
+            .unwrap();
+        let state = db.state();
+
+        let (status, body) = send(app(state.clone()), Method::GET, "/1?fields=id,name", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(body, json!({ "id": 1, "name": "alice" }));
+
+        let (status, body) = send(app(state.clone()), Method::GET, "/1?fields=age", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(body, json!({ "age": 30 }));
+
+        // not cached, so it really came from the database
+        assert!(state.cache.get(UserId::Serial(1)).is_none());
+
+        let (status, body) = send(app(state.clone()), Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(body, json!({ "id": 1, "name": "alice", "age": 30 }));
+
+        // and picked from the cached user now
+        let (status, body) = send(app(state), Method::GET, "/1?fields=name", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(body, json!({ "name": "alice" }));
+    }
+
+    #[tokio::test]
+    async fn unknown_fields_are_bad_request() {
+        let (status, _) = send(
+            app(mock_state()),
+            Method::GET,
+            "/1?fields=id,deleted_at",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
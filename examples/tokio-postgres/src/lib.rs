@@ -0,0 +1,2169 @@
+//! Example of using a tokio-postgres connection pool from handlers.
+//!
+//! Create the tables and run with
+//!
+//! ```not_rust
+//! psql -f examples/tokio-postgres/schema.sql
+//! cargo run -p example-tokio-postgres
+//! ```
+//!
+//! Or, for UUIDs rather than integers as user ids,
+//!
+//! ```not_rust
+//! psql -f examples/tokio-postgres/schema-uuid.sql
+//! UUID_IDS=true cargo run -p example-tokio-postgres
+//! ```
+//!
+//! Or, without a database, keeping users in memory:
+//!
+//! ```not_rust
+//! cargo run -p example-tokio-postgres --features mock-db
+//! ```
+//!
+//! Set `BIND_UDS` to listen on a Unix domain socket rather than TCP, say for a
+//! proxy on the same host:
+//!
+//! ```not_rust
+//! BIND_UDS=/tmp/example-tokio-postgres.sock cargo run -p example-tokio-postgres
+//! curl --unix-socket /tmp/example-tokio-postgres.sock http://localhost/live
+//! ```
+//!
+//! Benchmark the handlers against the mock database, and reading users from
+//! rows against `TEST_DATABASE_URL` if it is set, with
+//!
+//! ```not_rust
+//! cargo bench -p example-tokio-postgres --features mock-db
+//! ```
+
+// the Postgres setup goes unused with the mock database
+#![cfg_attr(feature = "mock-db", allow(dead_code))]
+// for the `json!` listing every setting in `admin::config_report`
+#![recursion_limit = "256"]
+
+mod admin;
+mod age;
+#[doc(hidden)]
+pub mod bench;
+mod breaker;
+mod db;
+mod deadline;
+mod deprecation;
+mod disabled_routes;
+mod envelope;
+mod error;
+mod error_detail;
+mod fields;
+mod limit;
+mod naming;
+mod problem;
+mod purge;
+mod repository;
+mod request_id;
+mod security_headers;
+mod static_errors;
+mod supervise;
+#[cfg(test)]
+mod test_helpers;
+mod trace_context;
+#[cfg(unix)]
+mod unix_socket;
+mod user_id;
+mod users;
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, RequestParts},
+    handler::{get, patch, post},
+    http::{HeaderValue, Method, Request, StatusCode},
+    response::IntoResponse,
+    routing::BoxRoute,
+    AddExtensionLayer, Json, Router,
+};
+use bb8::RunError;
+use breaker::CircuitBreaker;
+use db::{Conn, ConnectionPool, RawText};
+use deadline::Deadline;
+use disabled_routes::DisabledRoutes;
+use error::AppError;
+use error_detail::ErrorDetail;
+use fields::{PartialUser, UserField};
+use repository::{PostgresUserRepository, UserRepository};
+use request_id::RequestId;
+use user_id::UserId;
+use users::QueryParams;
+
+use hyper::server;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::Builder,
+    sync::{oneshot, watch},
+};
+use tokio_postgres::Row;
+use tower::{BoxError, ServiceBuilder};
+use tower_http::trace::TraceLayer;
+use tracing::Span;
+
+use serde::{Deserialize, Serialize};
+
+/// Run the server, configured from the environment, until it is shut down.
+pub fn serve() {
+    let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+
+    rt.block_on(async {
+        // Set the RUST_LOG, if it hasn't been explicitly defined
+        if std::env::var_os("RUST_LOG").is_none() {
+            std::env::set_var("RUST_LOG", "example_tokio_postgres=debug,tower_http=debug")
+        }
+
+        tracing_subscriber::fmt::init();
+
+        let start = Instant::now();
+        let config = match Config::from_env() {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!(%err, "failed to load config");
+                std::process::exit(1);
+            }
+        };
+        tracing::info!(elapsed_ms = elapsed_ms(start), "config loaded");
+
+        run(config, shutdown_signal(), flush_telemetry).await;
+    });
+}
+
+/// Serve requests until `shutdown_signal` completes, then let the requests in
+/// flight and the background jobs finish, and finally call `flush`.
+///
+/// Each stage of starting and stopping is logged at `info`, with how long it
+/// took in `elapsed_ms`, so the logs show where a slow start is stuck.
+async fn run<S, F, Fut>(config: Config, shutdown_signal: S, flush: F)
+where
+    S: Future<Output = ()> + Send + 'static,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let start = Instant::now();
+    let addr = config.addr;
+    let flush_timeout = config.shutdown_flush_timeout;
+
+    #[cfg(not(feature = "mock-db"))]
+    let state = Arc::new(AppState::starting(config));
+    #[cfg(feature = "mock-db")]
+    let state = {
+        let users = repository::MockUsers::new(config.uuid_ids);
+        Arc::new(AppState::mock(config, users))
+    };
+
+    let (draining, drain_start) = oneshot::channel();
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let graceful_shutdown = async move {
+        shutdown_signal.await;
+        tracing::info!("draining requests in flight and background jobs");
+        draining.send(Instant::now()).ok();
+        shutdown.send(true).ok();
+    };
+
+    // run it with hyper
+    let bind_start = Instant::now();
+    let bind_uds = state.config.bind_uds.as_ref();
+    let server: Pin<Box<dyn Future<Output = hyper::Result<()>> + Send>> = match bind_uds {
+        #[cfg(unix)]
+        Some(path) => {
+            let (incoming, socket_file) = unix_socket::bind(path)
+                .unwrap_or_else(|err| panic!("failed to bind {}: {}", path.display(), err));
+            tracing::info!(
+                path = %path.display(),
+                elapsed_ms = elapsed_ms(bind_start),
+                "server bound"
+            );
+            // there are no client addresses, so no per IP limits either
+            let server = configure_server(axum::Server::builder(incoming), &state.config)
+                .serve(app(state.clone()).into_make_service())
+                .with_graceful_shutdown(graceful_shutdown);
+            Box::pin(async move {
+                let _socket_file = socket_file;
+                server.await
+            })
+        }
+        #[cfg(not(unix))]
+        Some(_) => panic!("BIND_UDS is only supported on Unix"),
+        None => {
+            let server = configure_server(axum::Server::bind(&addr), &state.config)
+                // so we know which client requests came from
+                .serve(app(state.clone()).into_make_service_with_connect_info::<SocketAddr, _>());
+            tracing::info!(
+                addr = %server.local_addr(),
+                elapsed_ms = elapsed_ms(bind_start),
+                "server bound"
+            );
+            Box::pin(server.with_graceful_shutdown(graceful_shutdown))
+        }
+    };
+
+    // we're already serving so probes get answers while we connect
+    #[cfg(not(feature = "mock-db"))]
+    let background = tokio::spawn(connect(state, start, shutdown_rx));
+    // nothing to connect to, or to run background jobs against
+    #[cfg(feature = "mock-db")]
+    let background = {
+        tracing::info!(elapsed_ms = elapsed_ms(start), "startup complete");
+        drop(state);
+        tokio::spawn(async move { drop(shutdown_rx) })
+    };
+
+    server.await.unwrap();
+
+    // let background jobs finish what they are doing
+    background.await.unwrap();
+
+    let drain_start = drain_start.await.unwrap_or(start);
+    tracing::info!(elapsed_ms = elapsed_ms(drain_start), "stopped");
+
+    // last, so it includes everything logged while stopping
+    if tokio::time::timeout(flush_timeout, flush()).await.is_err() {
+        tracing::warn!(?flush_timeout, "gave up flushing telemetry");
+    }
+}
+
+/// Write out telemetry that is still buffered before we exit.
+///
+/// That's only our logs for now, a metrics or trace exporter would be flushed
+/// here as well. It may be dropped half way if it takes longer than
+/// `SHUTDOWN_FLUSH_TIMEOUT_SECS`.
+async fn flush_telemetry() {
+    use std::io::Write;
+
+    // stdout blocks, and may do so for long if it is a pipe nobody reads
+    let flushed = tokio::task::spawn_blocking(|| std::io::stdout().flush()).await;
+    if let Ok(Err(err)) = flushed {
+        eprintln!("failed to flush logs: {}", err);
+    }
+}
+
+/// Apply our settings for client connections to `builder`.
+fn configure_server<I>(builder: server::Builder<I>, config: &Config) -> server::Builder<I> {
+    let builder = builder.http1_keepalive(config.http_keepalive);
+
+    // zero means no timeout
+    if config.header_read_timeout > Duration::from_secs(0) {
+        builder.http1_header_read_timeout(config.header_read_timeout)
+    } else {
+        builder
+    }
+}
+
+/// Set up the connection pool, retrying until the database is reachable, and
+/// then run the background jobs that need it until `shutdown` changes.
+///
+/// Starting up is complete once we have the pool, `start` is when it began.
+async fn connect(state: SharedState, start: Instant, mut shutdown: watch::Receiver<bool>) {
+    let config = &state.config;
+    let mut delay = Duration::from_millis(100);
+    let pool_start = Instant::now();
+
+    let pool = loop {
+        match state.build_pool(config.database_config()).await {
+            Ok(pool) => break pool,
+            Err(err) => tracing::warn!(%err, ?delay, "failed to connect to the database, retrying"),
+        }
+
+        if tokio::time::timeout(delay, shutdown.changed())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        delay = (delay * 2).min(Duration::from_secs(10));
+    };
+
+    tracing::info!(
+        max_size = config.pool_max_size,
+        connections = pool.state().connections,
+        elapsed_ms = elapsed_ms(pool_start),
+        "pool built"
+    );
+    state.set_pool(pool);
+    tracing::info!(elapsed_ms = elapsed_ms(start), "startup complete");
+
+    let purge_shutdown = shutdown.clone();
+    supervise::supervise("purge", config.task_restart_max_delay, shutdown, || {
+        purge::run(
+            state.clone(),
+            config.soft_delete_retention,
+            config.purge_interval,
+            purge_shutdown.clone(),
+        )
+    })
+    .await;
+}
+
+#[cfg(unix)]
+// clippy thinks something inside `tokio::select!` is too new for our MSRV
+#[allow(clippy::incompatible_msrv)]
+async fn shutdown_signal() {
+    use std::io;
+    use tokio::signal::unix::SignalKind;
+
+    async fn terminate() -> io::Result<()> {
+        tokio::signal::unix::signal(SignalKind::terminate())?
+            .recv()
+            .await;
+        Ok(())
+    }
+
+    tokio::select! {
+        _ = terminate() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+    tracing::info!("signal received");
+}
+
+#[cfg(windows)]
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install CTRL+C handler");
+    tracing::info!("signal received");
+}
+
+/// Having a function that produces our app makes it easy to call it from tests
+/// without having to create an HTTP server.
+fn app(state: SharedState) -> Router<BoxRoute> {
+    let max_concurrency = state.config.max_concurrency;
+    let max_concurrency_per_ip = state.config.max_concurrency_per_ip;
+    let max_in_flight = max_concurrency + state.config.max_queued_requests;
+    let max_uri_len = state.config.max_uri_len;
+    let request_timeout = state.config.request_timeout;
+    let timeouts = state.clone();
+    let envelope = state.config.envelope_responses;
+    let error_detail = state.config.error_detail;
+    let json_camel_case = state.config.json_camel_case;
+    let content_security_policy = state.config.content_security_policy.clone();
+    let static_errors = Arc::new(static_errors::StaticErrors::default());
+    let overloaded = static_errors.overloaded.clone();
+    let disabled_routes = state.disabled_routes.clone();
+
+    Router::new()
+        .route("/", post(using_connection_extractor))
+        .route("/:id", get(using_connection_pool_extractor))
+        // added after `/:id` so they get to match first
+        .route("/routes", get(list_routes))
+        .route("/live", get(live))
+        .route("/admin/pool", get(admin::pool_stats))
+        .route("/debug/config", get(admin::config))
+        .route(
+            "/admin/disabled-routes",
+            get(admin::disabled_routes).put(admin::set_disabled_routes),
+        )
+        .route("/admin/db/reload", post(admin::reload_database))
+        // every route adds to the router's type, box what we have so far so
+        // it doesn't take the compiler forever
+        .boxed()
+        .route("/users", get(users::list_users).post(users::create_user))
+        // added before `/users/:id` so they get to match first
+        .route("/users/bulk-update", post(users::bulk_update_users))
+        .route("/users/import", post(users::import_users))
+        .route("/users/lookup", post(users::lookup_users))
+        .route(
+            "/users/by-name/:name",
+            get(users::user_by_name).put(users::upsert_user_by_name),
+        )
+        .route(
+            "/users/:id",
+            patch(users::patch_user)
+                .put(users::replace_user)
+                .delete(users::delete_user),
+        )
+        .route("/users/:id/similar", get(users::similar_users))
+        .layer(
+            ServiceBuilder::new()
+                .map_response(move |response| static_errors::fill_in(response, &static_errors))
+                .into_inner(),
+        )
+        // requests beyond the limit wait for a free slot rather than all
+        // competing for a database connection at once
+        .layer(tower::layer::layer_fn(move |svc| {
+            limit::limit_concurrency(svc, max_concurrency, request_timeout)
+        }))
+        // handle errors from middleware
+        .handle_error(handle_error)
+        // turn requests away rather than queueing more than we can get
+        // through before they time out
+        .layer(tower::layer::layer_fn(move |svc| {
+            limit::LoadShed::new(svc, max_in_flight, overloaded.clone())
+        }))
+        // outside the concurrency limit so a client can't fill its queue
+        .layer(tower::layer::layer_fn(move |svc| {
+            limit::PerIpLimit::new(svc, max_concurrency_per_ip)
+        }))
+        .layer(tower::layer::layer_fn(move |svc| {
+            limit::MaxUriLen::new(svc, max_uri_len)
+        }))
+        .layer(tower::layer::layer_fn(move |svc| {
+            disabled_routes::DisableRoutes::new(svc, disabled_routes.clone())
+        }))
+        .layer(
+            ServiceBuilder::new()
+                .and_then(move |response| naming::rename(response, json_camel_case))
+                .into_inner(),
+        )
+        .layer(
+            ServiceBuilder::new()
+                .and_then(move |response| envelope::wrap(response, envelope))
+                .into_inner(),
+        )
+        .layer(
+            ServiceBuilder::new()
+                .map_response(move |response| error_detail::redact(response, error_detail))
+                .into_inner(),
+        )
+        .layer(tower::layer::layer_fn(problem::ProblemJson::new))
+        .layer(tower::layer::layer_fn(deprecation::Deprecations::new))
+        .layer(
+            ServiceBuilder::new()
+                // on every response, including errors, unless disabled
+                .map_response(move |response| match &content_security_policy {
+                    Some(policy) => security_headers::add(response, policy),
+                    None => response,
+                })
+                .into_inner(),
+        )
+        .layer(
+            ServiceBuilder::new()
+                // continue the caller's trace, or start a new one, and record
+                // it on the span of every request
+                .map_request(request_id::assign)
+                .map_request(move |request: Request<_>| {
+                    let timeout = timeouts.config.request_timeout_for(&request);
+                    deadline::assign(request, timeout)
+                })
+                .map_request(trace_context::propagate)
+                .layer(TraceLayer::new_for_http().make_span_with(trace_context::make_span))
+                .into_inner(),
+        )
+        .layer(AddExtensionLayer::new(state))
+        .boxed()
+}
+
+/// A description of a route, used by `GET /routes`.
+#[derive(Debug, Serialize)]
+struct RouteInfo {
+    path: &'static str,
+    methods: &'static [&'static str],
+    description: &'static str,
+    /// Set if the route is deprecated, to the HTTP date after which it may
+    /// be removed. See [`deprecation`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sunset: Option<&'static str>,
+}
+
+/// The routes mounted by [`app`]. Remember to update this when adding routes.
+const ROUTES: &[RouteInfo] = &[
+    RouteInfo {
+        path: "/",
+        methods: &["POST"],
+        description: "fetch the first user, using the `Users` extractor. Deprecated, list users \
+                      with `GET /users?limit=1` instead",
+        sunset: Some("Wed, 30 Jun 2027 00:00:00 GMT"),
+    },
+    RouteInfo {
+        path: "/:id",
+        methods: &["GET"],
+        description: "fetch a user by id, or some of their fields with `?fields=id,name`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/routes",
+        methods: &["GET"],
+        description: "list the available routes",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/live",
+        methods: &["GET"],
+        description: "liveness probe, responds even while we are connecting to the database",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/admin/pool",
+        methods: &["GET"],
+        description: "connection pool statistics, requires `ADMIN_TOKEN`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/debug/config",
+        methods: &["GET"],
+        description: "the configuration in effect, requires `ADMIN_TOKEN`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/admin/disabled-routes",
+        methods: &["GET", "PUT"],
+        description: "the routes turned off with `DISABLED_ROUTES`, which `PUT` changes, \
+                      requires `ADMIN_TOKEN`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/admin/db/reload",
+        methods: &["POST"],
+        description: "reconnect to the database with fresh credentials, requires `ADMIN_TOKEN`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/users",
+        methods: &["GET", "POST"],
+        description: "list users, a page at a time with `?limit=` and `?offset=`, or create one \
+                      with `?dry_run=true` support, once per `Idempotency-Key`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/users/bulk-update",
+        methods: &["POST"],
+        description: "update every user matching a filter, requires `ADMIN_TOKEN`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/users/import",
+        methods: &["POST"],
+        description: "create users from newline delimited JSON, supports `?stop_on_error=true`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/users/lookup",
+        methods: &["POST"],
+        description: "the users with the ids in `{\"ids\": [...]}`, up to `MAX_LOOKUP_IDS` \
+                      of them",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/users/by-name/:name",
+        methods: &["GET", "PUT"],
+        description: "the user with exactly this name, `409` if several users have it. `PUT` \
+                      sets their age, creating them if needed",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/users/:id",
+        methods: &["PUT", "PATCH", "DELETE"],
+        description: "replace, update or delete a user, supports `?dry_run=true`",
+        sunset: None,
+    },
+    RouteInfo {
+        path: "/users/:id/similar",
+        methods: &["GET"],
+        description: "other users closest in age to the user, supports `?limit=`",
+        sunset: None,
+    },
+];
+
+/// The route in [`ROUTES`] that handles `path`.
+///
+/// Like the router, routes with fixed segments win over ones with
+/// parameters, so `/routes` isn't handled by `/:id`.
+fn route_for(path: &str) -> Option<&'static RouteInfo> {
+    ROUTES
+        .iter()
+        .filter(|route| route_matches(route.path, path))
+        .max_by_key(|route| {
+            route
+                .path
+                .split('/')
+                .filter(|s| !s.starts_with(':'))
+                .count()
+        })
+}
+
+fn route_matches(route: &str, path: &str) -> bool {
+    let route = route.split('/').collect::<Vec<_>>();
+    let path = path.split('/').collect::<Vec<_>>();
+
+    route.len() == path.len()
+        && route.iter().zip(&path).all(|(route, path)| {
+            if route.starts_with(':') {
+                !path.is_empty()
+            } else {
+                route == path
+            }
+        })
+}
+
+/// Lists the routes in [`ROUTES`], unless disabled with `EXPOSE_ROUTES=false`.
+async fn list_routes(
+    Extension(state): Extension<SharedState>,
+) -> Result<Json<&'static [RouteInfo]>, StatusCode> {
+    if state.config.expose_routes {
+        Ok(Json(ROUTES))
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// Handler for `GET /live`. Always succeeds as long as we're serving requests.
+async fn live() -> &'static str {
+    "ok"
+}
+
+/// Settings read from the environment at startup.
+#[derive(Debug, Clone)]
+struct Config {
+    database_url: String,
+    /// Read `database_url` from this file rather than `DATABASE_URL`, and
+    /// again on `POST /admin/db/reload`. For credentials that are rotated by
+    /// rewriting the file, like those written by a Vault agent.
+    database_url_file: Option<PathBuf>,
+    addr: SocketAddr,
+    /// Listen on a Unix domain socket at this path rather than on `addr`.
+    bind_uds: Option<PathBuf>,
+    cache_ttl: Duration,
+    max_concurrency: usize,
+    /// How many requests a single IP may have in flight, zero for no limit.
+    max_concurrency_per_ip: usize,
+    /// How many requests may wait for one of the `max_concurrency` slots,
+    /// beyond that they are rejected with `503 Service Unavailable`.
+    max_queued_requests: usize,
+    /// Longest path and query string we accept, longer ones get `414 URI Too
+    /// Long`.
+    max_uri_len: usize,
+    request_timeout: Duration,
+    /// How long clients get to send a request's headers, zero for as long as
+    /// they like. Clients sending them a byte at a time are disconnected
+    /// after this long, so they can't tie up connections. hyper also starts
+    /// the timer while waiting for the next request on a kept alive
+    /// connection, so this is the idle timeout for those as well.
+    header_read_timeout: Duration,
+    /// Keep connections open between requests.
+    http_keepalive: bool,
+    /// Don't start a query with less than this much of `request_timeout`
+    /// left, zero to always start it.
+    min_query_budget: Duration,
+    expose_routes: bool,
+    /// Routes that respond with `503 Service Unavailable`, named by their
+    /// path in [`ROUTES`]. See [`disabled_routes`].
+    disabled_routes: Vec<String>,
+    pg_schema: String,
+    /// The role requests run their queries as, with `SET ROLE`, rather than
+    /// as the user we connect as. Must be in `PG_ALLOWED_ROLES`.
+    ///
+    /// Users aren't cached with this or `pg_admin_role` set, see
+    /// [`AppState::starting`].
+    pg_request_role: Option<String>,
+    /// Like `pg_request_role` but for requests sending `ADMIN_TOKEN`, which
+    /// use `pg_request_role` too if this isn't set.
+    pg_admin_role: Option<String>,
+    envelope_responses: bool,
+    /// Whether `500 Internal Server Error` responses say what went wrong, see
+    /// [`error_detail`].
+    error_detail: ErrorDetail,
+    /// Name JSON fields in camelCase rather than snake_case, see [`naming`].
+    json_camel_case: bool,
+    pool_max_size: u32,
+    /// How long to wait for a connection from the pool.
+    pool_timeout: Duration,
+    /// Failed connection attempts in a row before we stop trying for
+    /// `breaker_cooldown`, zero to keep trying. See [`CircuitBreaker`].
+    breaker_threshold: u32,
+    breaker_cooldown: Duration,
+    /// Token required by the `/admin` endpoints, which are disabled if it
+    /// isn't set.
+    admin_token: Option<String>,
+    /// Reject request bodies with fields we don't know about.
+    reject_unknown_fields: bool,
+    /// The most rows any query returning many is allowed to return.
+    max_rows: usize,
+    /// How many rows endpoints returning many return if the client doesn't
+    /// ask for a `?limit=`, at most `max_rows`.
+    default_page_limit: usize,
+    /// How many ids `POST /users/lookup` accepts.
+    max_lookup_ids: usize,
+    /// How many of those are looked up per query.
+    lookup_chunk_size: usize,
+    /// How long any one statement may run for.
+    statement_timeout: Duration,
+    /// Like `statement_timeout` but for listing users, which reads the
+    /// whole table. At most `list_request_timeout`.
+    list_statement_timeout: Duration,
+    /// Like `request_timeout` but for `GET /users`, so its queries get to
+    /// use `list_statement_timeout`.
+    list_request_timeout: Duration,
+    /// The `Content-Security-Policy` sent with every response, along with other
+    /// security headers. `None` if `SECURITY_HEADERS=false`.
+    content_security_policy: Option<HeaderValue>,
+    /// How long soft-deleted users are kept before being purged, zero to keep
+    /// them forever.
+    soft_delete_retention: Duration,
+    purge_interval: Duration,
+    /// The longest background jobs like the purge wait to be restarted after
+    /// panicking, see [`supervise`].
+    task_restart_max_delay: Duration,
+    /// How long to wait for buffered telemetry to be written out when
+    /// shutting down, see [`flush_telemetry`].
+    shutdown_flush_timeout: Duration,
+    /// Send TCP keepalives on idle database connections, so firewalls and NAT
+    /// gateways that drop quiet connections leave them alone.
+    pg_keepalives: bool,
+    /// How long a connection is idle before keepalives are sent. Defaults to a
+    /// minute, well below the few minutes after which NAT gateways commonly
+    /// forget idle connections.
+    pg_keepalives_idle: Duration,
+    /// Tag statements with the id of the request that ran them. They can't use
+    /// the prepared statement cache when this is on.
+    query_request_ids: bool,
+    /// Users have `uuid` rather than `serial` ids.
+    uuid_ids: bool,
+}
+
+impl Config {
+    /// Read the configuration from the environment, see [`Config::from_vars`].
+    fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|key| {
+            std::env::var_os(key).map(|value| value.to_string_lossy().into_owned())
+        })
+    }
+
+    /// Read the configuration from the variables `var` looks up, validating
+    /// every setting before any of them is used.
+    ///
+    /// Rather than stop at the first invalid setting this carries on with its
+    /// default, so the error lists everything that needs fixing.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut vars = Vars {
+            var: &var,
+            errors: Vec::new(),
+        };
+
+        let database_url_file = vars.var("DATABASE_URL_FILE").map(PathBuf::from);
+        let (database_url_key, database_url) = match &database_url_file {
+            Some(path) => (
+                "DATABASE_URL_FILE",
+                read_database_url(path).unwrap_or_else(|err| {
+                    vars.invalid("DATABASE_URL_FILE", err);
+                    String::new()
+                }),
+            ),
+            None => (
+                "DATABASE_URL",
+                vars.var("DATABASE_URL")
+                    .unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string()),
+            ),
+        };
+        if let Err(err) = database_url.parse::<tokio_postgres::Config>() {
+            vars.invalid(database_url_key, err);
+        }
+
+        let security_headers = vars.get("SECURITY_HEADERS", true);
+        let content_security_policy = vars.get(
+            "CONTENT_SECURITY_POLICY",
+            HeaderValue::from_static("default-src 'none'; frame-ancestors 'none'"),
+        );
+
+        let config = Self {
+            database_url,
+            database_url_file,
+            addr: vars.get("BIND_ADDR", SocketAddr::from(([127, 0, 0, 1], 3000))),
+            bind_uds: vars.var("BIND_UDS").map(PathBuf::from),
+            cache_ttl: Duration::from_secs(vars.get("CACHE_TTL_SECS", 60)),
+            max_concurrency: vars.get("MAX_CONCURRENT_REQUESTS", 64),
+            max_concurrency_per_ip: vars.get("MAX_CONCURRENT_REQUESTS_PER_IP", 16),
+            max_queued_requests: vars.get("MAX_QUEUED_REQUESTS", 256),
+            max_uri_len: vars.get("MAX_URI_LEN", 8 * 1024),
+            request_timeout: Duration::from_secs(vars.get("REQUEST_TIMEOUT_SECS", 10)),
+            header_read_timeout: Duration::from_secs(vars.get("HEADER_READ_TIMEOUT_SECS", 10)),
+            http_keepalive: vars.get("HTTP_KEEPALIVE", true),
+            min_query_budget: Duration::from_millis(vars.get("MIN_QUERY_BUDGET_MS", 250)),
+            expose_routes: vars.get("EXPOSE_ROUTES", true),
+            disabled_routes: vars.list("DISABLED_ROUTES", ""),
+            pg_schema: vars.get("PG_SCHEMA", "public".to_string()),
+            pg_request_role: vars.var("PG_REQUEST_ROLE"),
+            pg_admin_role: vars.var("PG_ADMIN_ROLE"),
+            envelope_responses: vars.get("ENVELOPE_RESPONSES", false),
+            error_detail: vars.get("ERROR_DETAIL", ErrorDetail::Safe),
+            json_camel_case: vars.get("JSON_CAMEL_CASE", false),
+            pool_max_size: vars.get("PG_POOL_MAX_SIZE", 10),
+            pool_timeout: Duration::from_secs(vars.get("PG_POOL_TIMEOUT_SECS", 30)),
+            breaker_threshold: vars.get("BREAKER_THRESHOLD", 5),
+            breaker_cooldown: Duration::from_secs(vars.get("BREAKER_COOLDOWN_SECS", 10)),
+            admin_token: vars.var("ADMIN_TOKEN"),
+            reject_unknown_fields: vars.get("REJECT_UNKNOWN_FIELDS", false),
+            max_rows: vars.get("MAX_ROWS", 10_000),
+            default_page_limit: vars.get("DEFAULT_PAGE_LIMIT", 100),
+            max_lookup_ids: vars.get("MAX_LOOKUP_IDS", 10_000),
+            lookup_chunk_size: vars.get("LOOKUP_CHUNK_SIZE", 500),
+            statement_timeout: Duration::from_millis(vars.get("STATEMENT_TIMEOUT_MS", 5_000)),
+            list_statement_timeout: Duration::from_millis(
+                vars.get("LIST_STATEMENT_TIMEOUT_MS", 30_000),
+            ),
+            list_request_timeout: Duration::from_secs(vars.get("LIST_REQUEST_TIMEOUT_SECS", 35)),
+            content_security_policy: Some(content_security_policy).filter(|_| security_headers),
+            soft_delete_retention: Duration::from_secs(
+                vars.get("SOFT_DELETE_RETENTION_DAYS", 30) * 24 * 60 * 60,
+            ),
+            purge_interval: Duration::from_secs(vars.get("PURGE_INTERVAL_SECS", 60 * 60)),
+            task_restart_max_delay: Duration::from_secs(
+                vars.get("TASK_RESTART_MAX_DELAY_SECS", 60),
+            ),
+            shutdown_flush_timeout: Duration::from_secs(vars.get("SHUTDOWN_FLUSH_TIMEOUT_SECS", 5)),
+            query_request_ids: vars.get("QUERY_REQUEST_IDS", false),
+            uuid_ids: vars.get("UUID_IDS", false),
+            pg_keepalives: vars.get("PG_KEEPALIVES", true),
+            pg_keepalives_idle: Duration::from_secs(vars.get("PG_KEEPALIVES_IDLE_SECS", 60)),
+        };
+
+        // bb8 panics on an empty pool, and we can't look anything up in
+        // chunks of nothing
+        if config.pool_max_size == 0 {
+            vars.invalid("PG_POOL_MAX_SIZE", "must be at least 1");
+        }
+        if config.lookup_chunk_size == 0 {
+            vars.invalid("LOOKUP_CHUNK_SIZE", "must be at least 1");
+        }
+        // the request would time out before the query does
+        if config.list_statement_timeout > config.list_request_timeout {
+            vars.invalid(
+                "LIST_STATEMENT_TIMEOUT_MS",
+                "must be at most LIST_REQUEST_TIMEOUT_SECS",
+            );
+        }
+
+        if let Err(err) = disabled_routes::validate(&config.disabled_routes) {
+            vars.invalid("DISABLED_ROUTES", err);
+        }
+
+        // only schemas listed in `PG_ALLOWED_SCHEMAS` may be used
+        let allowed_schemas = vars.list("PG_ALLOWED_SCHEMAS", "public");
+        if let Err(err) = db::validate_schema(&config.pg_schema, &allowed_schemas) {
+            vars.invalid("PG_SCHEMA", err);
+        }
+
+        // and only roles listed in `PG_ALLOWED_ROLES`, none by default
+        let allowed_roles = vars.list("PG_ALLOWED_ROLES", "");
+        for (key, role) in &[
+            ("PG_REQUEST_ROLE", &config.pg_request_role),
+            ("PG_ADMIN_ROLE", &config.pg_admin_role),
+        ] {
+            if let Some(role) = role {
+                if let Err(err) = db::validate_role(role, &allowed_roles) {
+                    vars.invalid(key, err);
+                }
+            }
+        }
+
+        if vars.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(vars.errors))
+        }
+    }
+
+    /// How long `request` may take, `list_request_timeout` for `GET /users`
+    /// and `request_timeout` for everything else.
+    fn request_timeout_for<B>(&self, request: &Request<B>) -> Duration {
+        if request.method() == Method::GET && request.uri().path() == "/users" {
+            self.list_request_timeout
+        } else {
+            self.request_timeout
+        }
+    }
+
+    /// The settings to connect to the database with, from `DATABASE_URL`.
+    ///
+    /// The keepalive settings take precedence over any in `DATABASE_URL`.
+    fn database_config(&self) -> tokio_postgres::Config {
+        self.database_config_for(&self.database_url).unwrap()
+    }
+
+    /// Like [`Config::database_config`] but for `database_url`, which may
+    /// have changed since we started. See [`Config::fresh_database_url`].
+    fn database_config_for(
+        &self,
+        database_url: &str,
+    ) -> Result<tokio_postgres::Config, tokio_postgres::Error> {
+        let mut config: tokio_postgres::Config = database_url.parse()?;
+        config
+            .keepalives(self.pg_keepalives)
+            .keepalives_idle(self.pg_keepalives_idle);
+        Ok(config)
+    }
+
+    /// The database URL as it is now, read again from `DATABASE_URL_FILE` if
+    /// set or else from the environment.
+    fn fresh_database_url(&self) -> Result<String, String> {
+        match &self.database_url_file {
+            Some(path) => read_database_url(path),
+            None => {
+                Ok(std::env::var("DATABASE_URL")
+                    .unwrap_or_else(|_| DEFAULT_DATABASE_URL.to_string()))
+            }
+        }
+    }
+}
+
+const DEFAULT_DATABASE_URL: &str =
+    "host=localhost user=postgres password=postgrespassword dbname=postgres";
+
+/// The contents of `DATABASE_URL_FILE`, without the trailing newline editors
+/// and secret stores like to add.
+fn read_database_url(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map(|url| url.trim().to_string())
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))
+}
+
+/// Every invalid setting found by [`Config::from_vars`], so they can be fixed
+/// in one go.
+#[derive(Debug)]
+struct ConfigError(Vec<String>);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration: {}", self.0.join(", "))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The variables [`Config::from_vars`] reads, and what was wrong with them.
+struct Vars<'a> {
+    var: &'a dyn Fn(&str) -> Option<String>,
+    errors: Vec<String>,
+}
+
+impl Vars<'_> {
+    fn var(&self, key: &str) -> Option<String> {
+        (self.var)(key)
+    }
+
+    /// Parse the variable `key`, falling back to `default` if it isn't set or
+    /// is invalid.
+    fn get<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        match self.var(key) {
+            Some(value) => value.parse().unwrap_or_else(|err| {
+                self.invalid(key, err);
+                default
+            }),
+            None => default,
+        }
+    }
+
+    /// A comma separated list, without empty items.
+    fn list(&self, key: &str, default: &str) -> Vec<String> {
+        self.var(key)
+            .as_deref()
+            .unwrap_or(default)
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    }
+
+    fn invalid(&mut self, key: &str, err: impl std::fmt::Display) {
+        self.errors.push(format!("{}: {}", key, err));
+    }
+}
+
+/// Everything our handlers share. It's added to every request with a single
+/// `AddExtensionLayer` and extracted with `Extension<SharedState>`.
+struct AppState {
+    /// Empty until we have connected to the database at startup, and
+    /// replaced by `POST /admin/db/reload`.
+    pool: RwLock<Option<ConnectionPool>>,
+    /// Used instead of Postgres by the handlers written against
+    /// [`UserRepository`], if set.
+    #[cfg(any(test, feature = "mock-db"))]
+    mock_users: Option<repository::MockUsers>,
+    config: Config,
+    cache: UserCache,
+    /// Counts the connections made and discarded by the pool.
+    connection_stats: Arc<db::ConnectionStats>,
+    /// Opened by the pool failing to connect.
+    breaker: Arc<CircuitBreaker>,
+    /// Starts out as `DISABLED_ROUTES` and is changed by
+    /// `PUT /admin/disabled-routes`.
+    disabled_routes: Arc<DisabledRoutes>,
+}
+
+impl AppState {
+    /// State with a connection pool that's ready to use.
+    #[cfg(test)]
+    fn new(pool: ConnectionPool, config: Config) -> Self {
+        let state = Self::starting(config);
+        state.set_pool(pool);
+        state
+    }
+
+    /// State without a connection pool, for while we are connecting.
+    fn starting(config: Config) -> Self {
+        // the cache doesn't know which role read a user, so it would hand
+        // users read as `PG_ADMIN_ROLE` to requests that may not see them
+        let cache_ttl = if config.pg_request_role.is_some() || config.pg_admin_role.is_some() {
+            Duration::from_secs(0)
+        } else {
+            config.cache_ttl
+        };
+        let cache = UserCache::new(cache_ttl);
+        let breaker = CircuitBreaker::new(config.breaker_threshold, config.breaker_cooldown);
+        let disabled_routes = DisabledRoutes::default();
+        disabled_routes
+            .set(&config.disabled_routes)
+            .expect("invalid DISABLED_ROUTES");
+
+        Self {
+            pool: RwLock::new(None),
+            #[cfg(any(test, feature = "mock-db"))]
+            mock_users: None,
+            config,
+            cache,
+            connection_stats: Arc::default(),
+            breaker: Arc::new(breaker),
+            disabled_routes: Arc::new(disabled_routes),
+        }
+    }
+
+    /// State that keeps users in `users` rather than Postgres. Handlers that
+    /// aren't written against [`UserRepository`] aren't available.
+    #[cfg(any(test, feature = "mock-db"))]
+    fn mock(config: Config, users: repository::MockUsers) -> Self {
+        Self {
+            mock_users: Some(users),
+            ..Self::starting(config)
+        }
+    }
+
+    fn set_pool(&self, pool: ConnectionPool) {
+        let mut current = self.pool.write().unwrap();
+        if current.is_some() {
+            panic!("pool was already set");
+        }
+        *current = Some(pool);
+    }
+
+    /// Start using `pool` rather than the current pool, returning that.
+    ///
+    /// Requests that already have a connection from the old pool keep it
+    /// until they're done. The old pool is closed once the last of them and
+    /// the caller have dropped theirs.
+    fn replace_pool(&self, pool: ConnectionPool) -> Option<ConnectionPool> {
+        self.pool.write().unwrap().replace(pool)
+    }
+
+    /// Build a pool connecting with `database_config` and our other
+    /// settings, once one of its connections has connected.
+    ///
+    /// That waits up to `pool_timeout` while connecting fails, and the
+    /// connection stays in the pool for the first request to use.
+    async fn build_pool(
+        &self,
+        database_config: tokio_postgres::Config,
+    ) -> Result<ConnectionPool, RunError<tokio_postgres::Error>> {
+        let config = &self.config;
+        let manager = db::Manager::new(database_config)
+            .search_path(&config.pg_schema)
+            .statement_timeout(config.statement_timeout)
+            .stats(self.connection_stats.clone())
+            .breaker(self.breaker.clone());
+        let pool = bb8::Pool::builder()
+            .max_size(config.pool_max_size)
+            .connection_timeout(config.pool_timeout)
+            .build(manager)
+            .await?;
+
+        // bb8 only connects while building a pool that keeps idle
+        // connections, which ours doesn't, so it can't tell us whether the
+        // database is reachable
+        drop(pool.get().await?);
+        Ok(pool)
+    }
+
+    /// The connection pool, or `503 Service Unavailable` if we're still
+    /// starting up.
+    ///
+    /// This is a handle to the pool as it is now, later requests may get
+    /// another one if it is replaced in the meantime.
+    fn pool(&self) -> Result<ConnectionPool, AppError> {
+        #[cfg(any(test, feature = "mock-db"))]
+        if self.mock_users.is_some() {
+            return Err(AppError::new(
+                StatusCode::NOT_IMPLEMENTED,
+                "not available with the mock database",
+            ));
+        }
+
+        self.pool.read().unwrap().clone().ok_or_else(|| {
+            AppError::unavailable("starting up, try again shortly", Duration::from_secs(1))
+        })
+    }
+
+    /// Check out a connection from the pool.
+    ///
+    /// If none becomes available within `PG_POOL_TIMEOUT_SECS` clients get a
+    /// `503 Service Unavailable` telling them to retry after that long.
+    ///
+    /// With `QUERY_REQUEST_IDS` the connection's statements are tagged with
+    /// `request_id`.
+    ///
+    /// While the database is unreachable, see [`CircuitBreaker`], we answer
+    /// `503` without waiting for the pool.
+    ///
+    /// How long getting the connection took is recorded as `acquire_ms` on
+    /// the request span.
+    ///
+    /// Queries run as `PG_REQUEST_ROLE`, or `PG_ADMIN_ROLE` for requests from
+    /// an admin, if set.
+    ///
+    /// If getting the connection took so long that less than
+    /// `MIN_QUERY_BUDGET_MS` is left before `deadline` we answer `503` right
+    /// away, rather than start a query that will be cut off by the request
+    /// timeout anyway.
+    async fn conn(&self, context: ConnContext) -> Result<Conn, AppError> {
+        let ConnContext {
+            request_id,
+            deadline,
+            admin,
+        } = context;
+        let pool = self.pool()?;
+        self.breaker.check()?;
+        let start = Instant::now();
+        let result = pool.get_owned().await;
+        Span::current().record("acquire_ms", elapsed_ms(start));
+        let mut conn = result.map_err(|err| match err {
+            RunError::TimedOut => AppError::unavailable(
+                "timed out waiting for a database connection",
+                self.config.pool_timeout,
+            ),
+            RunError::User(err) => AppError::internal(err),
+        })?;
+        self.breaker.record_success();
+
+        if let Some(deadline) = deadline {
+            let remaining = deadline.remaining();
+            // zero means no minimum
+            if remaining < self.config.min_query_budget {
+                tracing::warn!(
+                    ?remaining,
+                    "waited too long for a connection, not starting the query"
+                );
+                return Err(AppError::unavailable(
+                    "not enough time left to run the query",
+                    Duration::from_secs(1),
+                ));
+            }
+        }
+
+        // always set so we don't keep the id of the last request to use it
+        let request_id = request_id.filter(|_| self.config.query_request_ids);
+        conn.set_request_id(request_id.map(|RequestId(id)| id));
+
+        let config = &self.config;
+        let role = match &config.pg_admin_role {
+            Some(role) if admin => Some(role),
+            _ => config.pg_request_role.as_ref(),
+        };
+        if let Some(role) = role {
+            conn.set_role(role).await?;
+        }
+
+        Ok(conn)
+    }
+
+    /// The users, in Postgres using a connection checked out like
+    /// [`AppState::conn`] or in memory with the `mock-db` feature.
+    async fn users(&self, context: ConnContext) -> Result<Box<dyn UserRepository>, AppError> {
+        #[cfg(any(test, feature = "mock-db"))]
+        if let Some(users) = &self.mock_users {
+            return Ok(Box::new(users.clone()));
+        }
+
+        let conn = self.conn(context).await?;
+        Ok(Box::new(PostgresUserRepository::new(
+            conn,
+            self.config.list_statement_timeout,
+        )))
+    }
+}
+
+type SharedState = Arc<AppState>;
+
+/// A small in-memory cache of users we have recently looked up by id.
+///
+/// A `ttl` of zero turns it off.
+struct UserCache {
+    ttl: Duration,
+    users: Mutex<HashMap<UserId, (Instant, User)>>,
+}
+
+impl UserCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, id: UserId) -> Option<User> {
+        let mut users = self.users.lock().unwrap();
+
+        match users.get(&id) {
+            Some((inserted_at, user)) if inserted_at.elapsed() < self.ttl => Some(user.clone()),
+            Some(_) => {
+                users.remove(&id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, user: User) {
+        if self.ttl == Duration::from_secs(0) {
+            return;
+        }
+        self.users
+            .lock()
+            .unwrap()
+            .insert(user.id, (Instant::now(), user));
+    }
+
+    fn remove(&self, id: UserId) {
+        self.users.lock().unwrap().remove(&id);
+    }
+
+    fn clear(&self) {
+        self.users.lock().unwrap().clear();
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct User {
+    id: UserId,
+    name: String,
+    /// `i64` so widening the column to `bigint` doesn't break reads. Not an
+    /// [`Age`](age::Age) since what's already stored shouldn't stop us reading
+    /// it, even if it is out of range.
+    age: i64,
+}
+
+impl User {
+    /// Read a user from a row with `id`, `name` and `age` columns.
+    fn from_row(row: &Row) -> Result<Self, AppError> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            name: read_name(row)?,
+            age: read_age(row)?,
+        })
+    }
+}
+
+// we can exact the shared state, and with it the connection pool, with `Extension`
+async fn using_connection_pool_extractor(
+    Extension(state): Extension<SharedState>,
+    context: ConnContext,
+    id: UserId,
+    QueryParams(params): QueryParams<FieldsParams>,
+) -> Result<(StatusCode, impl IntoResponse), AppError> {
+    let fields = match &params.fields {
+        Some(fields) => UserField::parse_list(fields)?,
+        None => UserField::ALL.to_vec(),
+    };
+
+    if let Some(user) = state.cache.get(id) {
+        return Ok((
+            StatusCode::FOUND,
+            Json(PartialUser::from_user(user, &fields)),
+        ));
+    }
+
+    let users = state.users(context).await?;
+
+    // only whole users are cached
+    let user = if fields == UserField::ALL {
+        let user = timed_query(users.get(id)).await?;
+        state.cache.insert(user.clone());
+        PartialUser::from_user(user, &fields)
+    } else {
+        timed_query(users.get_fields(id, &fields)).await?
+    };
+
+    Ok((StatusCode::FOUND, Json(user)))
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldsParams {
+    /// A comma separated list of the fields to return, all of them if not
+    /// set.
+    fields: Option<String>,
+}
+
+/// What a connection is checked out for, see [`AppState::conn`].
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnContext {
+    request_id: Option<RequestId>,
+    deadline: Option<Deadline>,
+    /// Whether the request sent `ADMIN_TOKEN`, which picks the role its
+    /// queries run as.
+    admin: bool,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for ConnContext
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(AppError::internal)?;
+
+        let extensions = req.extensions();
+        Ok(Self {
+            request_id: extensions.and_then(|extensions| extensions.get::<RequestId>().copied()),
+            deadline: extensions.and_then(|extensions| extensions.get::<Deadline>().copied()),
+            admin: admin::has_admin_token(req.headers(), &state.config),
+        })
+    }
+}
+
+// we can also write a custom extractor that grabs a connection from the pool,
+// wrapped in a repository. Which setup is appropriate depends on your
+// application
+//
+// hyper drops the handler's future if the client disconnects, which is safe
+// while we're waiting for a connection: bb8 hands the next free connection to
+// the next waiter that is still around, so an abandoned wait doesn't hold on
+// to one. Nothing here may spawn the wait onto another task, or it would
+// outlive the request.
+//
+// With the `mock-db` feature we get users kept in memory instead.
+struct Users(Box<dyn UserRepository>);
+
+#[async_trait]
+impl<B> FromRequest<B> for Users
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(AppError::internal)?;
+
+        let context = ConnContext::from_request(req).await?;
+        let users = state.users(context).await?;
+
+        Ok(Self(users))
+    }
+}
+
+async fn using_connection_extractor(
+    Users(users): Users,
+) -> Result<(StatusCode, Json<User>), AppError> {
+    let user = timed_query(users.first()).await?;
+    Ok((StatusCode::FOUND, Json(user)))
+}
+
+/// Run `query`, recording how long it took as `query_ms` on the request span.
+async fn timed_query<F>(query: F) -> F::Output
+where
+    F: Future,
+{
+    let start = Instant::now();
+    let output = query.await;
+    Span::current().record("query_ms", elapsed_ms(start));
+    output
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Read the `name` column, replacing any invalid UTF-8 rather than failing so
+/// one bad row doesn't break reads.
+fn read_name(row: &Row) -> Result<String, AppError> {
+    if let Ok(name) = row.try_get::<_, String>("name") {
+        return Ok(name);
+    }
+
+    let RawText(bytes) = row.try_get("name")?;
+    let name = String::from_utf8_lossy(bytes);
+    if let Cow::Owned(name) = &name {
+        tracing::warn!(%name, "`name` is not valid UTF-8, replaced invalid bytes");
+    }
+
+    Ok(name.into_owned())
+}
+
+/// Read the `age` column, which may be an `integer` or a `bigint`.
+fn read_age(row: &Row) -> Result<i64, AppError> {
+    match row.try_get::<_, i64>("age") {
+        Ok(age) => Ok(age),
+        Err(_) => Ok(row.try_get::<_, i32>("age")?.into()),
+    }
+}
+
+fn handle_error(error: BoxError) -> Result<impl IntoResponse, Infallible> {
+    if error.is::<tower::timeout::error::Elapsed>() {
+        return Ok(AppError::new(
+            StatusCode::REQUEST_TIMEOUT,
+            "request timed out",
+        ));
+    }
+
+    Ok(AppError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Unhandled internal error: {}", error),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{test_db, test_state, test_state_with, CapturedLogs};
+    use axum::body::Body;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    fn alice() -> User {
+        User {
+            id: UserId::Serial(1),
+            name: "alice".to_string(),
+            age: 30,
+        }
+    }
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        Config::from_vars(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn config_has_defaults_for_everything() {
+        let config = config_from(&[]).unwrap();
+
+        assert_eq!(config.addr, SocketAddr::from(([127, 0, 0, 1], 3000)));
+        assert_eq!(config.bind_uds, None);
+        assert_eq!(config.pool_max_size, 10);
+        assert_eq!(config.request_timeout, Duration::from_secs(10));
+        assert_eq!(config.list_request_timeout, Duration::from_secs(35));
+        assert_eq!(config.cache_ttl, Duration::from_secs(60));
+        assert_eq!(config.pg_schema, "public");
+        assert_eq!(config.pg_request_role, None);
+        assert!(config.disabled_routes.is_empty());
+        assert!(config.content_security_policy.is_some());
+        assert!(config.admin_token.is_none());
+    }
+
+    #[test]
+    fn config_is_read_from_vars() {
+        let config = config_from(&[
+            ("DATABASE_URL", "host=db user=app"),
+            ("BIND_ADDR", "0.0.0.0:8080"),
+            ("PG_POOL_MAX_SIZE", "20"),
+            ("REQUEST_TIMEOUT_SECS", "3"),
+            ("SECURITY_HEADERS", "false"),
+            ("PG_ALLOWED_ROLES", "app_reader, app_admin"),
+            ("PG_REQUEST_ROLE", "app_reader"),
+            ("DISABLED_ROUTES", "/users/import,"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.database_url, "host=db user=app");
+        assert_eq!(config.addr, SocketAddr::from(([0, 0, 0, 0], 8080)));
+        assert_eq!(config.pool_max_size, 20);
+        assert_eq!(config.request_timeout, Duration::from_secs(3));
+        assert!(config.content_security_policy.is_none());
+        assert_eq!(config.pg_request_role.as_deref(), Some("app_reader"));
+        assert_eq!(config.disabled_routes, vec!["/users/import"]);
+    }
+
+    #[test]
+    fn listing_users_gets_its_own_timeout() {
+        let config = Config::from_env().unwrap();
+        let timeout_for = |method: Method, uri: &str| {
+            let request = Request::builder().method(method).uri(uri).body(()).unwrap();
+            config.request_timeout_for(&request)
+        };
+
+        assert_eq!(
+            timeout_for(Method::GET, "/users?limit=10"),
+            config.list_request_timeout
+        );
+        assert_eq!(timeout_for(Method::POST, "/users"), config.request_timeout);
+        assert_eq!(timeout_for(Method::GET, "/users/1"), config.request_timeout);
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported() {
+        let err = config_from(&[
+            ("DATABASE_URL_FILE", "/nonexistent/database-url"),
+            ("BIND_ADDR", "localhost"),
+            ("PG_POOL_MAX_SIZE", "0"),
+            ("CACHE_TTL_SECS", "-1"),
+            ("HTTP_KEEPALIVE", "sure"),
+            ("PG_SCHEMA", "other"),
+            ("PG_REQUEST_ROLE", "postgres"),
+            ("LIST_REQUEST_TIMEOUT_SECS", "10"),
+        ])
+        .unwrap_err();
+
+        let keys = err
+            .0
+            .iter()
+            .map(|err| err.split(':').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                "DATABASE_URL_FILE",
+                "BIND_ADDR",
+                "CACHE_TTL_SECS",
+                "HTTP_KEEPALIVE",
+                "PG_POOL_MAX_SIZE",
+                "LIST_STATEMENT_TIMEOUT_MS",
+                "PG_SCHEMA",
+                "PG_REQUEST_ROLE",
+            ]
+        );
+        assert!(
+            err.to_string()
+                .contains("PG_POOL_MAX_SIZE: must be at least 1"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn state_is_accessible_from_handler() {
+        let state = test_state();
+        state.cache.insert(alice());
+
+        let response = app(state)
+            .oneshot(Request::builder().uri("/1").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FOUND);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "id": 1, "name": "alice", "age": 30 }));
+    }
+
+    #[tokio::test]
+    async fn acquire_and_query_times_are_recorded_on_request_span() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .execute("insert into users (name, age) values ('alice', 30)", &[])
+            .await
+            .unwrap();
+        let state = db.state();
+
+        // both through the pool and through the extractor
+        for (method, uri) in vec![(Method::GET, "/1"), (Method::POST, "/")] {
+            let logs = CapturedLogs::default();
+            let _guard = tracing::subscriber::set_default(logs.subscriber());
+
+            let response = app(state.clone())
+                .oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FOUND);
+
+            let logs = logs.contents();
+            assert!(logs.contains("acquire_ms="), "{}", logs);
+            assert!(logs.contains("query_ms="), "{}", logs);
+        }
+    }
+
+    #[tokio::test]
+    async fn queries_run_as_the_role_of_the_caller() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        // roles belong to the whole cluster rather than the test's schema, so
+        // they may be left over from an earlier run
+        db.client()
+            .await
+            .batch_execute(&format!(
+                "do $$ begin create role example_reader nologin; \
+                 exception when duplicate_object then null; end $$; \
+                 do $$ begin create role example_admin nologin; \
+                 exception when duplicate_object then null; end $$; \
+                 grant usage on schema {schema} to example_admin; \
+                 grant select on users to example_admin; \
+                 insert into users (name, age) values ('alice', 30)",
+                schema = db.schema(),
+            ))
+            .await
+            .unwrap();
+        let config = Config {
+            pg_request_role: Some("example_reader".to_string()),
+            pg_admin_role: Some("example_admin".to_string()),
+            admin_token: Some("secret".to_string()),
+            // so every checkout gets the same connection
+            pool_max_size: 1,
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+
+        let roles = |conn: Conn| async move {
+            let row = conn
+                .query_one("select current_user::text, session_user::text", &[])
+                .await
+                .unwrap();
+            (row.get::<_, String>(0), row.get::<_, String>(1))
+        };
+        let conn = state.conn(ConnContext::default()).await.unwrap();
+        assert_eq!(roles(conn).await.0, "example_reader");
+        let admin = ConnContext {
+            admin: true,
+            ..ConnContext::default()
+        };
+        let conn = state.conn(admin).await.unwrap();
+        assert_eq!(roles(conn).await.0, "example_admin");
+
+        // reset on the next checkout, even for code that doesn't set a role
+        let conn = state.pool().unwrap().get_owned().await.unwrap();
+        let (current_user, session_user) = roles(conn).await;
+        assert_eq!(current_user, session_user);
+
+        // the reader may not even look at the table
+        let get_alice = |token: Option<&str>| {
+            let mut request = Request::get("/1");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            app(state.clone()).oneshot(request.body(Body::empty()).unwrap())
+        };
+        let response = get_alice(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let response = get_alice(Some("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        // and isn't given what the admin read either
+        let response = get_alice(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn startup_and_shutdown_are_logged_in_order() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+
+        let config = Config {
+            addr: ([127, 0, 0, 1], 0).into(),
+            database_url: std::env::var("TEST_DATABASE_URL").unwrap(),
+            pg_schema: db.schema().to_string(),
+            ..Config::from_env().unwrap()
+        };
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run(
+            config,
+            async {
+                stopped.await.ok();
+            },
+            {
+                let flushes = flushes.clone();
+                move || async move {
+                    tracing::info!("flushing");
+                    flushes.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+        ));
+
+        for _ in 0..100 {
+            if logs.contents().contains("startup complete") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        stop.send(()).unwrap();
+        server.await.unwrap();
+
+        let logs = logs.contents();
+        let events = vec![
+            "server bound",
+            "pool built",
+            "startup complete",
+            "draining",
+            "stopped",
+            "flushing",
+        ];
+        let mut last = 0;
+        // there is no pool with the mock database
+        let has_pool = !cfg!(feature = "mock-db");
+        for event in events
+            .into_iter()
+            .filter(|&event| has_pool || event != "pool built")
+        {
+            let at = logs[last..]
+                .find(event)
+                .unwrap_or_else(|| panic!("no {:?} after the previous event: {}", event, logs));
+            last += at;
+        }
+        assert!(!has_pool || logs.contains("max_size=10"), "{}", logs);
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn incoming_traceparent_is_recorded_on_request_span() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+
+        let state = test_state();
+        state.cache.insert(alice());
+
+        let response = app(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/1")
+                    .header(
+                        "traceparent",
+                        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+
+        let logs = logs.contents();
+        assert!(
+            logs.contains("trace_id=4bf92f3577b34da6a3ce929d0e0e4736"),
+            "{}",
+            logs
+        );
+        assert!(logs.contains("parent_id=00f067aa0ba902b7"), "{}", logs);
+    }
+
+    #[test]
+    fn keepalives_are_applied_to_database_config() {
+        let config = Config {
+            database_url: "host=localhost keepalives=0 keepalives_idle=7200".to_string(),
+            pg_keepalives: true,
+            pg_keepalives_idle: Duration::from_secs(30),
+            ..Config::from_env().unwrap()
+        };
+        let database_config = config.database_config();
+        assert!(database_config.get_keepalives());
+        assert_eq!(
+            database_config.get_keepalives_idle(),
+            Duration::from_secs(30)
+        );
+
+        let config = Config {
+            pg_keepalives: false,
+            ..config
+        };
+        assert!(!config.database_config().get_keepalives());
+    }
+
+    #[tokio::test]
+    async fn routes_are_listed() {
+        let response = app(test_state())
+            .oneshot(
+                Request::builder()
+                    .uri("/routes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let routes = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|route| (route["path"].clone(), route["methods"].clone()))
+            .collect::<Vec<_>>();
+        assert!(routes.contains(&(json!("/"), json!(["POST"]))));
+        assert!(routes.contains(&(json!("/:id"), json!(["GET"]))));
+        assert!(routes.contains(&(json!("/users"), json!(["GET", "POST"]))));
+    }
+
+    #[test]
+    fn paths_resolve_to_the_route_handling_them() {
+        let route = |path| route_for(path).map(|route| route.path);
+        assert_eq!(route("/"), Some("/"));
+        assert_eq!(route("/1"), Some("/:id"));
+        assert_eq!(route("/routes"), Some("/routes"));
+        assert_eq!(route("/users/by-name/alice"), Some("/users/by-name/:name"));
+        assert_eq!(route("/users/import"), Some("/users/import"));
+        assert_eq!(route("/users/1"), Some("/users/:id"));
+        assert_eq!(route("/users/1/similar"), Some("/users/:id/similar"));
+        assert_eq!(route("/users/"), None);
+        assert_eq!(route("/nope/nope"), None);
+    }
+
+    #[tokio::test]
+    async fn routes_can_be_hidden() {
+        let config = Config {
+            expose_routes: false,
+            ..Config::from_env().unwrap()
+        };
+
+        let response = app(test_state_with(config))
+            .oneshot(
+                Request::builder()
+                    .uri("/routes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn responses_can_be_enveloped() {
+        for (envelope_responses, expected) in vec![
+            (false, json!({ "id": 1, "name": "alice", "age": 30 })),
+            (
+                true,
+                json!({ "data": { "id": 1, "name": "alice", "age": 30 } }),
+            ),
+        ] {
+            let config = Config {
+                envelope_responses,
+                ..Config::from_env().unwrap()
+            };
+            let state = test_state_with(config);
+            state.cache.insert(alice());
+
+            let response = app(state)
+                .oneshot(Request::builder().uri("/1").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::FOUND);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_are_not_enveloped() {
+        let config = Config {
+            envelope_responses: true,
+            ..Config::from_env().unwrap()
+        };
+
+        let response = app(test_state_with(config))
+            .oneshot(Request::builder().uri("/nan").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"id must be an integer");
+    }
+
+    #[tokio::test]
+    async fn exhausted_pool_responds_with_retry_after() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let config = Config {
+            pool_max_size: 1,
+            pool_timeout: Duration::from_millis(100),
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+
+        let _conn = state.pool().unwrap().get_owned().await.unwrap();
+        let response = app(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after = response.headers()["retry-after"].to_str().unwrap();
+        assert_eq!(retry_after.parse::<u64>().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn late_connections_are_not_used_for_queries() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let config = Config {
+            pool_max_size: 1,
+            request_timeout: Duration::from_secs(1),
+            min_query_budget: Duration::from_millis(500),
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+
+        let held = state.pool().unwrap().get_owned().await.unwrap();
+        let request = tokio::spawn(
+            app(state.clone()).oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/users")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{ "name": "alice", "age": 30 }"#))
+                    .unwrap(),
+            ),
+        );
+
+        // hand over the connection with less than the budget left
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        drop(held);
+        let response = request.await.unwrap().unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"not enough time left to run the query");
+
+        let users: i64 = db
+            .client()
+            .await
+            .query_one("select count(*) from users", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(users, 0);
+    }
+
+    #[tokio::test]
+    async fn abandoned_requests_dont_keep_connections() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let config = Config {
+            pool_max_size: 1,
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+
+        let held = state.pool().unwrap().get_owned().await.unwrap();
+
+        // like a client disconnecting while waiting for a connection
+        let request = app(state.clone()).oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/")
+                .body(Body::empty())
+                .unwrap(),
+        );
+        let result = tokio::time::timeout(Duration::from_millis(50), request).await;
+        assert!(result.is_err(), "request shouldn't get a connection yet");
+
+        drop(held);
+
+        // the connection went back to the pool rather than the abandoned request
+        let pool = state.pool().unwrap().state();
+        assert_eq!((pool.connections, pool.idle_connections), (1, 1));
+        tokio::time::timeout(Duration::from_millis(50), state.pool().unwrap().get())
+            .await
+            .expect("connection should be free")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn security_headers_are_set() {
+        for (security_headers, expected) in vec![
+            (
+                true,
+                Some((
+                    "nosniff",
+                    "DENY",
+                    "default-src 'none'; frame-ancestors 'none'",
+                )),
+            ),
+            (false, None),
+        ] {
+            let config = Config {
+                content_security_policy: Config::from_env()
+                    .unwrap()
+                    .content_security_policy
+                    .filter(|_| security_headers),
+                ..Config::from_env().unwrap()
+            };
+
+            let response = app(test_state_with(config))
+                .oneshot(
+                    Request::builder()
+                        .uri("/routes")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let headers = response.headers();
+            let actual = match (
+                headers.get("x-content-type-options"),
+                headers.get("x-frame-options"),
+                headers.get("content-security-policy"),
+            ) {
+                (Some(nosniff), Some(frame), Some(csp)) => Some((
+                    nosniff.to_str().unwrap(),
+                    frame.to_str().unwrap(),
+                    csp.to_str().unwrap(),
+                )),
+                (None, None, None) => None,
+                other => panic!("only some headers were set: {:?}", other),
+            };
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn user_is_read_from_row() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let client = db.client().await;
+
+        let row = client
+            .query_one("select 1 as id, 'alice' as name, 30 as age", &[])
+            .await
+            .unwrap();
+        let user = User::from_row(&row).unwrap();
+        assert_eq!(
+            (user.id, user.name.as_str(), user.age),
+            (UserId::Serial(1), "alice", 30)
+        );
+
+        let row = client
+            .query_one("select 1 as id, 'alice' as name", &[])
+            .await
+            .unwrap();
+        let err = User::from_row(&row).unwrap_err();
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn database_handlers_are_unavailable_while_starting() {
+        let state = Arc::new(AppState::starting(Config::from_env().unwrap()));
+
+        let response = app(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key("retry-after"));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"starting up, try again shortly");
+
+        let response = app(state)
+            .oneshot(Request::builder().uri("/live").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn startup_waits_for_the_database_to_be_reachable() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+        let config = Config {
+            database_url: "host=localhost port=1".to_string(),
+            pool_timeout: Duration::from_millis(100),
+            ..Config::from_env().unwrap()
+        };
+        let state = Arc::new(AppState::starting(config));
+
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let connecting = connect(state.clone(), Instant::now(), shutdown_rx);
+        assert!(tokio::time::timeout(Duration::from_millis(500), connecting)
+            .await
+            .is_err());
+
+        assert!(state.pool().is_err());
+        let logs = logs.contents();
+        assert!(
+            logs.contains("failed to connect to the database, retrying"),
+            "{}",
+            logs
+        );
+        assert!(!logs.contains("pool built"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn clients_sending_headers_slowly_are_disconnected() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Config {
+            header_read_timeout: Duration::from_millis(200),
+            ..Config::from_env().unwrap()
+        };
+        let state = test_state_with(config);
+        let builder = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)));
+        let server = configure_server(builder, &state.config).serve(app(state).into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /live HTTP/1.1\r\n").await.unwrap();
+
+        // the rest of the headers never arrive, so the server hangs up
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("connection wasn't closed")
+            .ok();
+    }
+}
@@ -0,0 +1,496 @@
+//! Caps how many requests are processed at the same time so a traffic spike
+//! doesn't pile onto the connection pool all at once, how many a single
+//! client may have in flight, and how many may be waiting before we start
+//! turning them away. Also caps how long URIs may be.
+
+use crate::{deadline::Timeout, error::AppError, static_errors::StaticError};
+use axum::{
+    body::{box_body, BoxBody},
+    extract::connect_info::ConnectInfo,
+    http::{Request, Response, StatusCode},
+    response::IntoResponse,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tower::{buffer::Buffer, limit::ConcurrencyLimit, BoxError, Service};
+
+/// How many requests may wait for a free slot before we stop accepting more.
+const QUEUE_SIZE: usize = 1024;
+
+/// Process at most `max` requests at once.
+///
+/// Excess requests wait in a queue, but never past their
+/// [`Deadline`](crate::deadline::Deadline) or else `timeout`, which covers
+/// both the time spent waiting and the time spent processing.
+pub fn limit_concurrency<S, R>(
+    svc: S,
+    max: usize,
+    timeout: Duration,
+) -> Timeout<Buffer<LogSaturation<ConcurrencyLimit<S>>, R>>
+where
+    S: Service<R> + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError> + Send + Sync,
+    R: Send + 'static,
+{
+    let limited = LogSaturation::new(ConcurrencyLimit::new(svc, max), max);
+    Timeout::new(Buffer::new(limited, QUEUE_SIZE), timeout)
+}
+
+/// Logs when the wrapped service stops being ready, which for
+/// [`ConcurrencyLimit`] means all slots are taken.
+pub struct LogSaturation<S> {
+    inner: S,
+    max: usize,
+    saturated: bool,
+}
+
+impl<S> LogSaturation<S> {
+    fn new(inner: S, max: usize) -> Self {
+        Self {
+            inner,
+            max,
+            saturated: false,
+        }
+    }
+}
+
+impl<S, R> Service<R> for LogSaturation<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = self.inner.poll_ready(cx);
+
+        match (&poll, self.saturated) {
+            (Poll::Pending, false) => {
+                tracing::warn!(
+                    max = self.max,
+                    "concurrency limit reached, queueing requests"
+                );
+                self.saturated = true;
+            }
+            (Poll::Ready(_), true) => {
+                tracing::debug!(max = self.max, "concurrency limit no longer saturated");
+                self.saturated = false;
+            }
+            _ => {}
+        }
+
+        poll
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+/// Middleware that rejects requests with `503 Service Unavailable` once `max`
+/// are in flight.
+///
+/// Set `max` to the concurrency limit plus however many requests may wait for
+/// a slot. Beyond that a request is likely to time out in the queue anyway, so
+/// it is cheaper for everyone to turn it away before it gets near the
+/// database.
+#[derive(Clone)]
+pub struct LoadShed<S> {
+    inner: S,
+    max: usize,
+    in_flight: Arc<AtomicUsize>,
+    /// What requests are turned away with.
+    overloaded: StaticError,
+}
+
+impl<S> LoadShed<S> {
+    pub fn new(inner: S, max: usize, overloaded: StaticError) -> Self {
+        Self {
+            inner,
+            max,
+            in_flight: Arc::default(),
+            overloaded,
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for LoadShed<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let slot = match Shed::acquire(&self.in_flight, self.max) {
+            Some(slot) => slot,
+            None => {
+                tracing::warn!(max = self.max, "too many requests in flight, shedding load");
+                let response = self.overloaded.response();
+                return Box::pin(async move { Ok(response) });
+            }
+        };
+
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let _slot = slot;
+            future.await
+        })
+    }
+}
+
+/// A request counted by [`LoadShed`] until dropped.
+struct Shed {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Shed {
+    fn acquire(in_flight: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                Some(count + 1).filter(|_| count < max)
+            })
+            .ok()?;
+
+        Some(Self {
+            in_flight: in_flight.clone(),
+        })
+    }
+}
+
+impl Drop for Shed {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware that rejects requests whose path and query string are longer
+/// than `max` bytes with `414 URI Too Long`.
+///
+/// Handlers parse the query string on every request, so this keeps a client
+/// from making them chew through megabytes of `?fields=`.
+#[derive(Clone)]
+pub struct MaxUriLen<S> {
+    inner: S,
+    max: usize,
+}
+
+impl<S> MaxUriLen<S> {
+    pub fn new(inner: S, max: usize) -> Self {
+        Self { inner, max }
+    }
+}
+
+impl<S, B> Service<Request<B>> for MaxUriLen<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let len = req
+            .uri()
+            .path_and_query()
+            .map_or(0, |path_and_query| path_and_query.as_str().len());
+        if len > self.max {
+            tracing::warn!(len, max = self.max, "rejecting request with a long URI");
+            let response = AppError::new(
+                StatusCode::URI_TOO_LONG,
+                format!("URI is longer than {} bytes", self.max),
+            )
+            .into_response()
+            .map(box_body);
+            return Box::pin(async move { Ok(response) });
+        }
+
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// Middleware that rejects requests with `429 Too Many Requests` while the
+/// client they came from already has `max` requests in flight.
+///
+/// This stops a single client from taking every slot of
+/// [`limit_concurrency`], for example by sending requests slowly. Clients are
+/// told apart by the address in [`ConnectInfo`], requests without one aren't
+/// limited.
+#[derive(Clone)]
+pub struct PerIpLimit<S> {
+    inner: S,
+    max: usize,
+    in_flight: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl<S> PerIpLimit<S> {
+    pub fn new(inner: S, max: usize) -> Self {
+        Self {
+            inner,
+            max,
+            in_flight: Arc::default(),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for PerIpLimit<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            // zero means no limit
+            .filter(|_| self.max > 0);
+
+        let slot = match ip {
+            Some(ip) => match InFlight::acquire(&self.in_flight, ip, self.max) {
+                Some(slot) => Some(slot),
+                None => {
+                    tracing::warn!(%ip, max = self.max, "too many concurrent requests from client");
+                    let response = AppError::too_many_requests(
+                        "too many concurrent requests",
+                        Duration::from_secs(1),
+                    )
+                    .into_response()
+                    .map(box_body);
+                    return Box::pin(async move { Ok(response) });
+                }
+            },
+            None => None,
+        };
+
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            // released when the response is ready, or if the request is
+            // dropped before then
+            let _slot = slot;
+            future.await
+        })
+    }
+}
+
+/// A request counted against its client's limit until dropped.
+struct InFlight {
+    in_flight: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl InFlight {
+    fn acquire(
+        in_flight: &Arc<Mutex<HashMap<IpAddr, usize>>>,
+        ip: IpAddr,
+        max: usize,
+    ) -> Option<Self> {
+        let mut counts = in_flight.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(Self {
+            in_flight: in_flight.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut counts = self.in_flight.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            // remove clients with nothing in flight so the map doesn't grow
+            // with every address we have ever seen
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_errors::StaticErrors;
+    use crate::{
+        app,
+        test_helpers::{mock_state, send},
+    };
+    use axum::http::Method;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn requests_beyond_the_limit_are_serialized() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let svc = {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            tower::service_fn(move |_: Request<()>| {
+                let in_flight = in_flight.clone();
+                let max_in_flight = max_in_flight.clone();
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, Infallible>(())
+                }
+            })
+        };
+        let svc = limit_concurrency(svc, 2, Duration::from_secs(5));
+
+        let calls = (0..10)
+            .map(|_| tokio::spawn(svc.clone().oneshot(Request::new(()))))
+            .collect::<Vec<_>>();
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    fn request_from(ip: [u8; 4]) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 1234))));
+        request
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_per_ip_limit_are_rejected() {
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let svc = {
+            let release = release.clone();
+            tower::service_fn(move |_: Request<()>| {
+                let release = release.clone();
+                async move {
+                    drop(release.acquire().await.unwrap());
+                    Ok::<_, Infallible>(Response::new(box_body(axum::body::Empty::new())))
+                }
+            })
+        };
+        let svc = PerIpLimit::new(svc, 2);
+
+        let held = (0..2)
+            .map(|_| tokio::spawn(svc.clone().oneshot(request_from([10, 0, 0, 1]))))
+            .collect::<Vec<_>>();
+        tokio::task::yield_now().await;
+
+        let response = svc
+            .clone()
+            .oneshot(request_from([10, 0, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // other clients have slots of their own
+        let other = tokio::spawn(svc.clone().oneshot(request_from([10, 0, 0, 2])));
+        tokio::task::yield_now().await;
+        release.add_permits(3);
+        let response = other.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        for call in held {
+            assert_eq!(call.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+
+        // nothing is left behind for clients without requests in flight
+        assert!(svc.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_threshold_are_shed() {
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let svc = {
+            let release = release.clone();
+            let calls = calls.clone();
+            tower::service_fn(move |_: Request<()>| {
+                let release = release.clone();
+                calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    drop(release.acquire().await.unwrap());
+                    Ok::<_, Infallible>(Response::new(box_body(axum::body::Empty::new())))
+                }
+            })
+        };
+        let svc = LoadShed::new(svc, 2, StaticErrors::default().overloaded);
+
+        let held = (0..2)
+            .map(|_| tokio::spawn(svc.clone().oneshot(Request::new(()))))
+            .collect::<Vec<_>>();
+        tokio::task::yield_now().await;
+
+        let response = svc.clone().oneshot(Request::new(())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key("retry-after"));
+        // shed before reaching the service
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        release.add_permits(2);
+        for call in held {
+            assert_eq!(call.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+
+        // and served again once the load is gone
+        release.add_permits(1);
+        let response = svc.clone().oneshot(Request::new(())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(svc.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn long_uris_are_rejected() {
+        let state = mock_state();
+        let max = state.config.max_uri_len;
+
+        let (status, _) = send(app(state.clone()), Method::GET, "/users?limit=10", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let uri = format!("/users?fields={}", "a".repeat(max));
+        let (status, _) = send(app(state), Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::URI_TOO_LONG);
+    }
+}
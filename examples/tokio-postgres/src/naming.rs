@@ -0,0 +1,135 @@
+//! Optionally names JSON fields in camelCase, like `createdAt`, for clients
+//! that expect JavaScript's convention rather than Rust's `created_at`.
+//!
+//! Our types keep their snake_case names. With `JSON_CAMEL_CASE` set the
+//! fields of responses are renamed on the way out, and
+//! [`JsonBody`](crate::users::JsonBody) renames the fields of request bodies
+//! back, so clients send the names they get.
+
+use crate::envelope::map_json_body;
+use axum::{
+    body::BoxBody,
+    http::{header, HeaderValue, Response},
+};
+use serde_json::{Map, Value};
+use std::convert::Infallible;
+
+/// `name` in camelCase, `created_at` becomes `createdAt`.
+pub fn camel_case(name: &str) -> String {
+    let mut words = name.split('_');
+    let mut camel_case = words.next().unwrap_or_default().to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel_case.extend(first.to_uppercase());
+            camel_case.push_str(chars.as_str());
+        }
+    }
+    camel_case
+}
+
+/// Rename the fields of `value`, and of any objects in it, to camelCase.
+pub fn to_camel_case(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| (camel_case(&key), to_camel_case(value)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(to_camel_case).collect()),
+        value => value,
+    }
+}
+
+/// Rename the fields of the object `body` from the camelCase names of
+/// `fields` to the names in `fields`.
+///
+/// Other fields are dropped, including ones with the snake_case names, since
+/// clients should only send the names they get. This is what serde does with
+/// `rename_all = "camelCase"`, which ignores fields it doesn't know.
+pub fn from_camel_case(body: Value, fields: &[&str]) -> Value {
+    let object = match body {
+        Value::Object(object) => object,
+        body => return body,
+    };
+
+    let mut renamed = Map::new();
+    for (key, value) in object {
+        if let Some(field) = fields.iter().find(|field| camel_case(field) == key) {
+            renamed.insert(field.to_string(), value);
+        }
+    }
+    Value::Object(renamed)
+}
+
+/// Rename the fields of JSON responses to camelCase if `enabled` is true.
+///
+/// Unlike the envelope this includes errors, so they are named the same way.
+pub async fn rename(
+    response: Response<BoxBody>,
+    enabled: bool,
+) -> Result<Response<BoxBody>, Infallible> {
+    if !enabled
+        || response.headers().get(header::CONTENT_TYPE)
+            != Some(&HeaderValue::from_static("application/json"))
+    {
+        return Ok(response);
+    }
+
+    Ok(map_json_body(response, to_camel_case).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{user_id::UserId, User};
+    use serde_json::json;
+
+    /// A [`User`] as it would be serialized with a `created_at` field.
+    fn alice_with_created_at() -> Value {
+        let alice = User {
+            id: UserId::Serial(1),
+            name: "alice".to_string(),
+            age: 30,
+        };
+        let mut user = serde_json::to_value(alice).unwrap();
+        user["created_at"] = json!("2021-08-01T12:00:00Z");
+        user
+    }
+
+    #[test]
+    fn names_are_converted_both_ways() {
+        assert_eq!(camel_case("created_at"), "createdAt");
+        assert_eq!(
+            camel_case("max_concurrent_requests"),
+            "maxConcurrentRequests"
+        );
+        assert_eq!(camel_case("name"), "name");
+
+        let snake_case = alice_with_created_at();
+        let camel_case = to_camel_case(json!({ "users": [snake_case.clone()] }));
+        assert_eq!(
+            camel_case,
+            json!({
+                "users": [{
+                    "id": 1,
+                    "name": "alice",
+                    "age": 30,
+                    "createdAt": "2021-08-01T12:00:00Z",
+                }],
+            })
+        );
+
+        let fields = &["id", "name", "age", "created_at"];
+        assert_eq!(
+            from_camel_case(camel_case["users"][0].clone(), fields),
+            snake_case
+        );
+        // snake_case names aren't accepted in camelCase mode
+        assert_eq!(
+            from_camel_case(snake_case, fields),
+            json!({ "id": 1, "name": "alice", "age": 30 })
+        );
+    }
+}
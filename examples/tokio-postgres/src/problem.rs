@@ -0,0 +1,170 @@
+//! Renders errors as RFC 7807 `application/problem+json` for clients that ask
+//! for it with `Accept: application/problem+json`. Everyone else gets the
+//! usual plain text errors.
+
+use crate::error::ErrorMessage;
+use axum::{
+    body::{box_body, BoxBody, Bytes, Full},
+    http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode},
+};
+use serde_json::{json, Value};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::Service;
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Problem details serialized ahead of time, which responses that are always
+/// the same carry so they needn't be rendered again.
+/// See [`static_errors`](crate::static_errors).
+#[derive(Debug, Clone)]
+pub struct ProblemBody(pub Bytes);
+
+/// Middleware that converts errors to problem details if the client accepts
+/// them.
+///
+/// Only responses from [`AppError`](crate::error::AppError) and
+/// [`StaticError`](crate::static_errors::StaticError) are converted, since we
+/// need their message for `detail`.
+#[derive(Clone)]
+pub struct ProblemJson<S> {
+    inner: S,
+}
+
+impl<S> ProblemJson<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, B> Service<Request<B>> for ProblemJson<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let wants_problem = accepts_problem_json(req.headers());
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            let response = future.await?;
+
+            if wants_problem {
+                Ok(into_problem(response))
+            } else {
+                Ok(response)
+            }
+        })
+    }
+}
+
+fn accepts_problem_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_range| media_range.split(';').next())
+        .any(|media_type| media_type.trim().eq_ignore_ascii_case(PROBLEM_JSON))
+}
+
+/// The problem details for an error with `status` and `detail`.
+pub fn details(status: StatusCode, detail: &str) -> Value {
+    json!({
+        "type": "about:blank",
+        "title": status.canonical_reason(),
+        "status": status.as_u16(),
+        "detail": detail,
+    })
+}
+
+fn into_problem(response: Response<BoxBody>) -> Response<BoxBody> {
+    let extensions = response.extensions();
+    let body = if let Some(ProblemBody(body)) = extensions.get::<ProblemBody>() {
+        body.clone()
+    } else if let Some(ErrorMessage(message)) = extensions.get::<ErrorMessage>() {
+        Bytes::from(details(response.status(), message).to_string())
+    } else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+
+    Response::from_parts(parts, box_body(Full::from(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app, test_helpers::test_state};
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    // admin endpoints respond with `404 Not Found` if `ADMIN_TOKEN` isn't set
+    fn not_found_request(accept: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().uri("/admin/pool");
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn errors_are_plain_text_by_default() {
+        let response = app(test_state())
+            .oneshot(not_found_request(Some("application/json")))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"not found");
+    }
+
+    #[tokio::test]
+    async fn errors_can_be_problem_json() {
+        let response = app(test_state())
+            .oneshot(not_found_request(Some(
+                "application/json, application/problem+json;q=0.9",
+            )))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "not found",
+            })
+        );
+    }
+}
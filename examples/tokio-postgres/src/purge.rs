@@ -0,0 +1,122 @@
+//! Background job that permanently deletes soft-deleted users once they have
+//! been deleted for longer than `SOFT_DELETE_RETENTION_DAYS`.
+
+use crate::{db::Conn, SharedState};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Purge soft-deleted users every `interval` until `shutdown` changes.
+///
+/// Does nothing if `retention` is zero. Each run uses the pool `state` has at
+/// the time, so it moves on to a new one when the pool is replaced.
+pub async fn run(
+    state: SharedState,
+    retention: Duration,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if retention == Duration::from_secs(0) {
+        tracing::debug!("purging soft-deleted users is disabled");
+        return;
+    }
+
+    loop {
+        // wait for the next run, or stop if we're shutting down in the
+        // meantime. A run in progress is left to finish since it is a single
+        // statement
+        if tokio::time::timeout(interval, shutdown.changed())
+            .await
+            .is_ok()
+        {
+            break;
+        }
+
+        match purge_once(&state, retention).await {
+            Ok(purged) => tracing::info!(purged, "purged soft-deleted users"),
+            Err(err) => tracing::error!(%err, "failed to purge soft-deleted users"),
+        }
+    }
+
+    tracing::debug!("stopped purging soft-deleted users");
+}
+
+async fn purge_once(state: &SharedState, retention: Duration) -> Result<u64, String> {
+    let pool = state.pool().map_err(|err| err.message().to_string())?;
+    let mut conn = pool.get_owned().await.map_err(|err| err.to_string())?;
+    // it may still have the id of the last request that used it
+    conn.set_request_id(None);
+    purge_deleted_users(&conn, retention)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Delete users that were soft-deleted more than `retention` ago, returning
+/// how many there were.
+pub async fn purge_deleted_users(
+    conn: &Conn,
+    retention: Duration,
+) -> Result<u64, tokio_postgres::Error> {
+    let statement = conn
+        .prepare_cached(
+            "delete from users where deleted_at < now() - make_interval(secs => $1::float8)",
+        )
+        .await?;
+
+    conn.execute(&statement, &[&retention.as_secs_f64()]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_db;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[tokio::test]
+    async fn only_users_deleted_before_retention_are_purged() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let client = db.client().await;
+        client
+            .batch_execute(
+                "insert into users (name, age, deleted_at) values \
+                 ('old', 30, now() - interval '10 days'), \
+                 ('recent', 30, now() - interval '1 day'), \
+                 ('active', 30, null)",
+            )
+            .await
+            .unwrap();
+
+        let conn = db.pool.get_owned().await.unwrap();
+        let purged = purge_deleted_users(&conn, 7 * DAY).await.unwrap();
+        assert_eq!(purged, 1);
+
+        let names = client
+            .query("select name from users order by id", &[])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<_, String>(0))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["recent", "active"]);
+    }
+
+    #[tokio::test]
+    async fn stops_on_shutdown() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        let job = tokio::spawn(run(db.state(), DAY, DAY, shutdown_rx));
+        shutdown.send(true).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), job)
+            .await
+            .expect("job didn't stop")
+            .unwrap();
+    }
+}
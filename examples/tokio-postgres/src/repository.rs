@@ -0,0 +1,1110 @@
+//! Storage for users, behind a trait so handlers don't depend on
+//! tokio-postgres. [`PostgresUserRepository`] is what we normally use and,
+//! with the `mock-db` feature, [`MockUsers`] keeps users in memory so no
+//! database is needed at all.
+
+use crate::{
+    age::Age,
+    db::Conn,
+    error::AppError,
+    fields::{self, PartialUser, UserField},
+    read_age,
+    user_id::UserId,
+    users::{BulkUpdate, NewUser, UpdateUser},
+    User,
+};
+use axum::{async_trait, http::StatusCode};
+use std::{collections::HashMap, convert::TryFrom, time::Duration};
+use tokio_postgres::{error::SqlState, types::ToSql, Row};
+
+/// The user operations handlers need.
+///
+/// Methods taking `dry_run` must not persist anything if it is set, but
+/// should otherwise behave the same, and return the same errors.
+///
+/// Deleted users are left out of everything.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Any one user.
+    async fn first(&self) -> Result<User, AppError>;
+
+    async fn get(&self, id: UserId) -> Result<User, AppError>;
+
+    /// Like [`UserRepository::get`] but only reading `fields`.
+    async fn get_fields(&self, id: UserId, fields: &[UserField]) -> Result<PartialUser, AppError> {
+        let user = self.get(id).await?;
+        Ok(PartialUser::from_user(user, fields))
+    }
+
+    /// The users with `ids`, in the same order, leaving out ids without a
+    /// user. `ids` must not repeat.
+    ///
+    /// They are read `chunk_size` at a time, so thousands of ids don't end
+    /// up in a single query.
+    async fn get_many(&self, ids: &[UserId], chunk_size: usize) -> Result<Vec<User>, AppError>;
+
+    /// The user named exactly `name`.
+    ///
+    /// Names aren't unique, so this fails with `409 Conflict` if there is
+    /// more than one, rather than pick one of them. See [`only_user`].
+    async fn get_by_name(&self, name: &str) -> Result<User, AppError>;
+
+    /// Users ordered by id, skipping the first `offset` and returning at most
+    /// `limit` of them.
+    async fn list(&self, offset: usize, limit: usize) -> Result<Page, AppError>;
+
+    /// Up to `limit` other users closest in age to the user with `id`,
+    /// closest first. Returns `None` if there is no user with `id`.
+    async fn similar(&self, id: UserId, limit: i64) -> Result<Option<Vec<User>>, AppError>;
+
+    /// Create `new_user`, unless a user was already created with
+    /// `idempotency_key`, in which case that user is returned as it is now.
+    async fn create(
+        &mut self,
+        new_user: &NewUser,
+        idempotency_key: Option<&str>,
+        dry_run: bool,
+    ) -> Result<User, AppError>;
+
+    /// Set the age of the user named exactly `name`, or create them if there
+    /// is no such user.
+    ///
+    /// Like [`UserRepository::get_by_name`] this fails with `409 Conflict` if
+    /// there are several users with `name`, and changes none of them.
+    async fn upsert_by_name(
+        &mut self,
+        name: &str,
+        age: Age,
+        dry_run: bool,
+    ) -> Result<Upserted, AppError>;
+
+    /// Create all of `new_users` at once, returning how many were created.
+    async fn create_many(&mut self, new_users: &[NewUser], dry_run: bool) -> Result<u64, AppError>;
+
+    /// Returns `None` if there is no user with `id`.
+    async fn update(
+        &mut self,
+        id: UserId,
+        changes: &UpdateUser,
+        dry_run: bool,
+    ) -> Result<Option<Updated>, AppError>;
+
+    /// Update every user matching the filter in `update`, returning how many
+    /// there were.
+    async fn bulk_update(&mut self, update: &BulkUpdate, dry_run: bool) -> Result<u64, AppError>;
+
+    /// Returns how many users were deleted, zero if there was no user with
+    /// `id`.
+    async fn delete(&mut self, id: UserId, dry_run: bool) -> Result<u64, AppError>;
+}
+
+/// A page of users, from [`UserRepository::list`].
+#[derive(Debug)]
+pub struct Page {
+    pub users: Vec<User>,
+    /// Set if there were more users after these.
+    pub has_more: bool,
+    /// How many users there are, counted at the same time as `users` were
+    /// read.
+    pub total: i64,
+}
+
+/// A user from [`UserRepository::upsert_by_name`].
+#[derive(Debug)]
+pub struct Upserted {
+    pub user: User,
+    /// Set if there was no user with the name yet.
+    pub created: bool,
+}
+
+/// A user from [`UserRepository::update`].
+#[derive(Debug)]
+pub struct Updated {
+    /// The user as they are now.
+    pub user: User,
+    /// How many rows the update changed, zero if `user` already had the
+    /// changes.
+    pub affected: u64,
+}
+
+/// The one user in `users`, which are all users with `name`.
+///
+/// `404 Not Found` if there are none and `409 Conflict` if there are several.
+fn only_user(mut users: Vec<User>, name: &str) -> Result<User, AppError> {
+    match users.len() {
+        0 => Err(AppError::new(StatusCode::NOT_FOUND, "user not found")),
+        1 => Ok(users.remove(0)),
+        _ => Err(AppError::new(
+            StatusCode::CONFLICT,
+            format!("more than one user is named {:?}", name),
+        )),
+    }
+}
+
+/// Users in Postgres, using a connection checked out for the current request.
+///
+/// Deleting only sets `deleted_at`, the rows are removed later by
+/// [`purge`](crate::purge).
+pub struct PostgresUserRepository {
+    conn: Conn,
+    /// Used rather than the pool's default for [`UserRepository::list`], which
+    /// reads the whole table.
+    list_statement_timeout: Duration,
+}
+
+impl PostgresUserRepository {
+    pub fn new(conn: Conn, list_statement_timeout: Duration) -> Self {
+        Self {
+            conn,
+            list_statement_timeout,
+        }
+    }
+
+    /// Run `query`, which selects a single user, with `{columns}` replaced by
+    /// the columns of `fields`.
+    ///
+    /// If the row can't be sent because `name` isn't valid UTF-8 the query is
+    /// run again reading the raw bytes of `name`, which
+    /// [`read_name`](crate::read_name) knows how to handle.
+    async fn query_one_user(
+        &self,
+        query: &str,
+        fields: &[UserField],
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, tokio_postgres::Error> {
+        let columns = fields::columns(fields, UserField::column);
+        let statement = self
+            .conn
+            .prepare_cached(&query.replace("{columns}", &columns))
+            .await?;
+
+        match self.conn.query_one(&statement, params).await {
+            Err(err) if err.code() == Some(&SqlState::CHARACTER_NOT_IN_REPERTOIRE) => {
+                tracing::warn!(%err, "row is not valid UTF-8, reading `name` as raw bytes");
+
+                let columns = fields::columns(fields, UserField::raw_column);
+                let statement = self
+                    .conn
+                    .prepare_cached(&query.replace("{columns}", &columns))
+                    .await?;
+                self.conn.query_one(&statement, params).await
+            }
+            result => result,
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepository for PostgresUserRepository {
+    async fn first(&self) -> Result<User, AppError> {
+        let row = self
+            .query_one_user(
+                "select {columns} from users where deleted_at is null limit 1",
+                UserField::ALL,
+                &[],
+            )
+            .await?;
+        User::from_row(&row)
+    }
+
+    async fn get(&self, id: UserId) -> Result<User, AppError> {
+        let row = self
+            .query_one_user(
+                "select {columns} from users where id = $1 and deleted_at is null",
+                UserField::ALL,
+                &[&id],
+            )
+            .await?;
+        User::from_row(&row)
+    }
+
+    async fn get_many(&self, ids: &[UserId], chunk_size: usize) -> Result<Vec<User>, AppError> {
+        // Postgres plans `= any($1)` with a huge array badly, so we send
+        // several smaller ones. In one transaction, so users deleted in
+        // between don't make it into some chunks but not others
+        let mut found = HashMap::with_capacity(ids.len());
+        self.conn
+            .with_read_tx(async {
+                for chunk in ids.chunks(chunk_size) {
+                    let limit = chunk.len() as i64;
+                    let rows = self
+                        .conn
+                        .query_limited(
+                            "select id, name, age from users \
+                             where id = any($1) and deleted_at is null limit $2",
+                            &[&chunk, &limit],
+                        )
+                        .await?;
+                    for row in &rows {
+                        let user = User::from_row(row)?;
+                        found.insert(user.id, user);
+                    }
+                }
+                Ok::<_, AppError>(())
+            })
+            .await?;
+
+        Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    async fn get_by_name(&self, name: &str) -> Result<User, AppError> {
+        // two are enough to tell the name isn't unique
+        let rows = self
+            .conn
+            .query_limited(
+                "select id, name, age from users where name = $1 and deleted_at is null \
+                 order by id limit 2",
+                &[&name],
+            )
+            .await?;
+        let users = rows.iter().map(User::from_row).collect::<Result<_, _>>()?;
+        only_user(users, name)
+    }
+
+    async fn get_fields(&self, id: UserId, fields: &[UserField]) -> Result<PartialUser, AppError> {
+        let row = self
+            .query_one_user(
+                "select {columns} from users where id = $1 and deleted_at is null",
+                fields,
+                &[&id],
+            )
+            .await?;
+        PartialUser::from_row(&row, fields)
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> Result<Page, AppError> {
+        // there are never more rows than this to skip anyway
+        let offset = i64::try_from(offset).unwrap_or(i64::MAX);
+        // one extra to find out if there are more, bound rather than part of
+        // the query so every page size shares one prepared statement
+        let fetch = i64::try_from(limit.saturating_add(1)).unwrap_or(i64::MAX);
+        let params: &[&(dyn ToSql + Sync)] = &[&offset, &fetch];
+        let reads = async {
+            let statement = self
+                .conn
+                .prepare_cached("select count(*) from users where deleted_at is null")
+                .await?;
+            let total: i64 = self.conn.query_one(&statement, &[]).await?.get(0);
+
+            let rows = self
+                .conn
+                .query_limited(
+                    "select id, name, age from users where deleted_at is null \
+                     order by id offset $1 limit $2",
+                    params,
+                )
+                .await?;
+            Ok((rows, total))
+        };
+        // in one transaction so users created in between don't make the
+        // total disagree with the page
+        let (mut rows, total) = self
+            .conn
+            .with_read_tx(
+                self.conn
+                    .with_statement_timeout(self.list_statement_timeout, reads),
+            )
+            .await?;
+
+        let has_more = rows.len() > limit;
+        rows.truncate(limit);
+        let users = rows.iter().map(User::from_row).collect::<Result<_, _>>()?;
+        Ok(Page {
+            users,
+            has_more,
+            total,
+        })
+    }
+
+    async fn similar(&self, id: UserId, limit: i64) -> Result<Option<Vec<User>>, AppError> {
+        // so the user can't be deleted in between
+        self.conn
+            .with_read_tx(async {
+                let statement = self
+                    .conn
+                    .prepare_cached("select age from users where id = $1 and deleted_at is null")
+                    .await?;
+                let age = match self.conn.query_opt(&statement, &[&id]).await? {
+                    Some(row) => read_age(&row)?,
+                    None => return Ok(None),
+                };
+
+                let rows = self
+                    .conn
+                    .query_limited(
+                        "select id, name, age from users where id <> $1 and deleted_at is null \
+                         order by abs(age - $2::bigint), id limit $3",
+                        &[&id, &age, &limit],
+                    )
+                    .await?;
+                let users = rows.iter().map(User::from_row).collect::<Result<_, _>>()?;
+
+                Ok(Some(users))
+            })
+            .await
+    }
+
+    async fn create(
+        &mut self,
+        new_user: &NewUser,
+        idempotency_key: Option<&str>,
+        dry_run: bool,
+    ) -> Result<User, AppError> {
+        // the no-op update makes `returning` give us the existing user on a
+        // conflict. Without a key there is never a conflict, since nulls are
+        // distinct
+        let statement = self
+            .conn
+            .prepare_cached(
+                "insert into users (name, age, idempotency_key) values ($1, $2::bigint, $3) \
+                 on conflict (idempotency_key) do update \
+                 set idempotency_key = excluded.idempotency_key \
+                 returning id, name, age",
+            )
+            .await?;
+
+        let conn = &*self.conn;
+        let statement = &statement;
+        conn.with_transaction(!dry_run, || async move {
+            let row = conn
+                .query_one(
+                    statement,
+                    &[&new_user.name, &i64::from(new_user.age), &idempotency_key],
+                )
+                .await?;
+            User::from_row(&row)
+        })
+        .await
+    }
+
+    async fn upsert_by_name(
+        &mut self,
+        name: &str,
+        age: Age,
+        dry_run: bool,
+    ) -> Result<Upserted, AppError> {
+        // names aren't unique, so there is no constraint for `on conflict` to
+        // use. Instead upserts of the same name take turns, each holding the
+        // lock until its transaction ends, so two of them can't both insert
+        let lock = self
+            .conn
+            .prepare_cached("select pg_advisory_xact_lock(hashtext($1))")
+            .await?;
+        let statement = self
+            .conn
+            .prepare_cached(
+                "with updated as ( \
+                     update users set age = $2::bigint \
+                     where name = $1 and deleted_at is null returning id, name, age \
+                 ), inserted as ( \
+                     insert into users (name, age) select $1, $2::bigint \
+                     where not exists (select from updated) returning id, name, age \
+                 ) \
+                 select id, name, age, false as created from updated \
+                 union all select id, name, age, true from inserted",
+            )
+            .await?;
+
+        let conn = &*self.conn;
+        let (lock, statement) = (&lock, &statement);
+        conn.with_transaction(!dry_run, || async move {
+            conn.execute(lock, &[&name]).await?;
+            let rows = conn.query(statement, &[&name, &i64::from(age)]).await?;
+            let created = rows.iter().any(|row| row.get("created"));
+            let users = rows
+                .iter()
+                .map(User::from_row)
+                .collect::<Result<Vec<_>, _>>()?;
+            // several users were updated if they share the name, which
+            // failing rolls back
+            let user = only_user(users, name)?;
+
+            Ok(Upserted { user, created })
+        })
+        .await
+    }
+
+    async fn create_many(&mut self, new_users: &[NewUser], dry_run: bool) -> Result<u64, AppError> {
+        let statement = self
+            .conn
+            .prepare_cached("insert into users (name, age) values ($1, $2::bigint)")
+            .await?;
+
+        let conn = &*self.conn;
+        let statement = &statement;
+        conn.with_transaction(!dry_run, || async move {
+            for user in new_users {
+                conn.execute(statement, &[&user.name, &i64::from(user.age)])
+                    .await?;
+            }
+            Ok(new_users.len() as u64)
+        })
+        .await
+    }
+
+    async fn update(
+        &mut self,
+        id: UserId,
+        changes: &UpdateUser,
+        dry_run: bool,
+    ) -> Result<Option<Updated>, AppError> {
+        // rows that already have the changes are left alone, so they don't
+        // count as affected. `existing` still sees the row as it was
+        let statement = self
+            .conn
+            .prepare_cached(
+                "with existing as ( \
+                     select id, name, age from users where id = $1 and deleted_at is null \
+                 ), updated as ( \
+                     update users set name = coalesce($2, name), \
+                     age = coalesce($3::bigint, age) \
+                     where id = $1 and deleted_at is null \
+                     and (name, age) is distinct from (coalesce($2, name), coalesce($3::bigint, age)) \
+                     returning id, name, age \
+                 ) \
+                 select id, name, age, 1::int8 as affected from updated \
+                 union all select id, name, age, 0 from existing \
+                 where not exists (select from updated)",
+            )
+            .await?;
+
+        let conn = &*self.conn;
+        let statement = &statement;
+        conn.with_transaction(!dry_run, || async move {
+            conn.query_opt(
+                statement,
+                &[&id, &changes.name, &changes.age.map(i64::from)],
+            )
+            .await?
+            .map(|row| {
+                Ok(Updated {
+                    user: User::from_row(&row)?,
+                    affected: row.try_get::<_, i64>("affected")? as u64,
+                })
+            })
+            .transpose()
+        })
+        .await
+    }
+
+    async fn bulk_update(&mut self, update: &BulkUpdate, dry_run: bool) -> Result<u64, AppError> {
+        // the check constraint on `age` rejects increments that make anyone's
+        // age negative
+        let statement = self
+            .conn
+            .prepare_cached(
+                "update users set name = coalesce($1, name), \
+                 age = coalesce($2::bigint, age + coalesce($3::bigint, 0)) \
+                 where ($4::bigint is null or age <= $4::bigint) and deleted_at is null",
+            )
+            .await?;
+
+        let conn = &*self.conn;
+        let statement = &statement;
+        conn.with_transaction(!dry_run, || async move {
+            Ok(conn
+                .execute(
+                    statement,
+                    &[
+                        &update.name,
+                        &update.age.map(i64::from),
+                        &update.increment_age,
+                        &update.max_age,
+                    ],
+                )
+                .await?)
+        })
+        .await
+    }
+
+    async fn delete(&mut self, id: UserId, dry_run: bool) -> Result<u64, AppError> {
+        let statement = self
+            .conn
+            .prepare_cached(
+                "update users set deleted_at = now() where id = $1 and deleted_at is null",
+            )
+            .await?;
+
+        let conn = &*self.conn;
+        let statement = &statement;
+        conn.with_transaction(!dry_run, || async move {
+            Ok(conn.execute(statement, &[&id]).await?)
+        })
+        .await
+    }
+}
+
+#[cfg(any(test, feature = "mock-db"))]
+pub use mock::MockUsers;
+
+#[cfg(any(test, feature = "mock-db"))]
+mod mock {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use uuid::Uuid;
+
+    /// Users in memory, shared by every request.
+    ///
+    /// Cloning gives another handle to the same users.
+    ///
+    /// Users get integer ids by default, like a `serial` column.
+    #[derive(Clone, Default)]
+    pub struct MockUsers {
+        inner: Arc<Mutex<Inner>>,
+    }
+
+    #[derive(Default)]
+    struct Inner {
+        users: HashMap<UserId, User>,
+        /// Like a `serial` column ids aren't reused, even after a dry run.
+        last_id: i32,
+        uuid_ids: bool,
+        /// Which user each `Idempotency-Key` created.
+        idempotency_keys: HashMap<String, UserId>,
+        /// Fail the next create as if the connection was lost after it went
+        /// through.
+        #[cfg(test)]
+        lose_connection: bool,
+    }
+
+    impl MockUsers {
+        /// Users with random UUIDs as ids if `uuid_ids` is set.
+        pub fn new(uuid_ids: bool) -> Self {
+            let inner = Inner {
+                uuid_ids,
+                ..Inner::default()
+            };
+            Self {
+                inner: Arc::new(Mutex::new(inner)),
+            }
+        }
+
+        /// Make the next create succeed but then fail as if the connection
+        /// to the database was lost before we heard back.
+        #[cfg(test)]
+        pub fn lose_connection_after_next_create(&self) {
+            self.inner.lock().unwrap().lose_connection = true;
+        }
+
+        fn not_found() -> AppError {
+            AppError::new(StatusCode::NOT_FOUND, "user not found")
+        }
+    }
+
+    #[async_trait]
+    impl UserRepository for MockUsers {
+        async fn first(&self) -> Result<User, AppError> {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .users
+                .values()
+                .min_by_key(|user| user.id)
+                .cloned()
+                .ok_or_else(Self::not_found)
+        }
+
+        async fn get(&self, id: UserId) -> Result<User, AppError> {
+            let inner = self.inner.lock().unwrap();
+            inner.users.get(&id).cloned().ok_or_else(Self::not_found)
+        }
+
+        async fn get_many(
+            &self,
+            ids: &[UserId],
+            _chunk_size: usize,
+        ) -> Result<Vec<User>, AppError> {
+            let inner = self.inner.lock().unwrap();
+            Ok(ids
+                .iter()
+                .filter_map(|id| inner.users.get(id).cloned())
+                .collect())
+        }
+
+        async fn get_by_name(&self, name: &str) -> Result<User, AppError> {
+            let inner = self.inner.lock().unwrap();
+            let users = inner
+                .users
+                .values()
+                .filter(|user| user.name == name)
+                .cloned()
+                .collect();
+            only_user(users, name)
+        }
+
+        async fn list(&self, offset: usize, limit: usize) -> Result<Page, AppError> {
+            let inner = self.inner.lock().unwrap();
+            let mut users = inner.users.values().cloned().collect::<Vec<_>>();
+            users.sort_by_key(|user| user.id);
+            let total = users.len() as i64;
+            users.drain(..offset.min(users.len()));
+
+            let has_more = users.len() > limit;
+            users.truncate(limit);
+            Ok(Page {
+                users,
+                has_more,
+                total,
+            })
+        }
+
+        async fn similar(&self, id: UserId, limit: i64) -> Result<Option<Vec<User>>, AppError> {
+            let inner = self.inner.lock().unwrap();
+            let age = match inner.users.get(&id) {
+                Some(user) => user.age,
+                None => return Ok(None),
+            };
+
+            let mut users = inner
+                .users
+                .values()
+                .filter(|user| user.id != id)
+                .cloned()
+                .collect::<Vec<_>>();
+            users.sort_by_key(|user| ((user.age - age).abs(), user.id));
+            users.truncate(limit as usize);
+            Ok(Some(users))
+        }
+
+        async fn create(
+            &mut self,
+            new_user: &NewUser,
+            idempotency_key: Option<&str>,
+            dry_run: bool,
+        ) -> Result<User, AppError> {
+            let mut inner = self.inner.lock().unwrap();
+            let existing = idempotency_key
+                .and_then(|key| inner.idempotency_keys.get(key))
+                .and_then(|id| inner.users.get(id));
+            let user = match existing {
+                Some(user) => user.clone(),
+                None => {
+                    let user = inner.insert(new_user, dry_run);
+                    if let (Some(key), false) = (idempotency_key, dry_run) {
+                        inner.idempotency_keys.insert(key.to_string(), user.id);
+                    }
+                    user
+                }
+            };
+
+            #[cfg(test)]
+            if std::mem::take(&mut inner.lose_connection) {
+                return Err(AppError::connection_lost("connection closed"));
+            }
+
+            Ok(user)
+        }
+
+        async fn upsert_by_name(
+            &mut self,
+            name: &str,
+            age: Age,
+            dry_run: bool,
+        ) -> Result<Upserted, AppError> {
+            let mut inner = self.inner.lock().unwrap();
+            let users = inner
+                .users
+                .values()
+                .filter(|user| user.name == name)
+                .cloned()
+                .collect::<Vec<_>>();
+            if users.is_empty() {
+                let new_user = NewUser {
+                    name: name.to_string(),
+                    age,
+                };
+                let user = inner.insert(&new_user, dry_run);
+                return Ok(Upserted {
+                    user,
+                    created: true,
+                });
+            }
+
+            let mut user = only_user(users, name)?;
+            user.age = age.into();
+            if !dry_run {
+                inner.users.insert(user.id, user.clone());
+            }
+            Ok(Upserted {
+                user,
+                created: false,
+            })
+        }
+
+        async fn create_many(
+            &mut self,
+            new_users: &[NewUser],
+            dry_run: bool,
+        ) -> Result<u64, AppError> {
+            let mut inner = self.inner.lock().unwrap();
+            for new_user in new_users {
+                inner.insert(new_user, dry_run);
+            }
+            Ok(new_users.len() as u64)
+        }
+
+        async fn update(
+            &mut self,
+            id: UserId,
+            changes: &UpdateUser,
+            dry_run: bool,
+        ) -> Result<Option<Updated>, AppError> {
+            let mut inner = self.inner.lock().unwrap();
+            let existing = match inner.users.get(&id) {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+
+            let mut user = existing.clone();
+            if let Some(name) = &changes.name {
+                user.name = name.clone();
+            }
+            if let Some(age) = changes.age {
+                user.age = age.into();
+            }
+            let affected = if (&user.name, user.age) == (&existing.name, existing.age) {
+                0
+            } else {
+                1
+            };
+            if !dry_run {
+                inner.users.insert(id, user.clone());
+            }
+
+            Ok(Some(Updated { user, affected }))
+        }
+
+        async fn bulk_update(
+            &mut self,
+            update: &BulkUpdate,
+            dry_run: bool,
+        ) -> Result<u64, AppError> {
+            let mut inner = self.inner.lock().unwrap();
+            let matches = |user: &User| update.max_age.map_or(true, |max_age| user.age <= max_age);
+
+            // like the check constraint on `age`, which fails the whole update
+            let increment = update.increment_age.unwrap_or(0);
+            if inner
+                .users
+                .values()
+                .any(|user| matches(user) && user.age + increment < 0)
+            {
+                return Err(AppError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "violates check constraint `users_age_check`",
+                ));
+            }
+
+            let mut updated = 0;
+            for user in inner.users.values_mut().filter(|user| matches(user)) {
+                updated += 1;
+
+                if dry_run {
+                    continue;
+                }
+                if let Some(name) = &update.name {
+                    user.name = name.clone();
+                }
+                if let Some(age) = update.age {
+                    user.age = age.into();
+                }
+                if let Some(increment) = update.increment_age {
+                    user.age += increment;
+                }
+            }
+
+            Ok(updated)
+        }
+
+        async fn delete(&mut self, id: UserId, dry_run: bool) -> Result<u64, AppError> {
+            let mut inner = self.inner.lock().unwrap();
+            if dry_run {
+                Ok(inner.users.contains_key(&id) as u64)
+            } else {
+                Ok(inner.users.remove(&id).is_some() as u64)
+            }
+        }
+    }
+
+    impl Inner {
+        fn insert(&mut self, new_user: &NewUser, dry_run: bool) -> User {
+            let id = if self.uuid_ids {
+                UserId::Uuid(Uuid::new_v4())
+            } else {
+                self.last_id += 1;
+                UserId::Serial(self.last_id)
+            };
+
+            let user = User {
+                id,
+                name: new_user.name.clone(),
+                age: new_user.age.into(),
+            };
+            if !dry_run {
+                self.users.insert(user.id, user.clone());
+            }
+
+            user
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        age::Age,
+        app,
+        test_helpers::{mock_state, send, test_db, test_db_with_encoding, TestDb},
+        AppState, Config,
+    };
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn handlers_work_against_mock_users() {
+        let state = mock_state();
+
+        let alice = json!({ "name": "alice", "age": 30 });
+        let (status, body) = send(app(state.clone()), Method::POST, "/users", Some(alice)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, json!({ "id": 1, "name": "alice", "age": 30 }));
+
+        let bob = json!({ "name": "bob", "age": 40 });
+        send(app(state.clone()), Method::POST, "/users", Some(bob)).await;
+
+        let (status, body) = send(
+            app(state.clone()),
+            Method::PATCH,
+            "/users/2",
+            Some(json!({ "age": 41 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "id": 2, "name": "bob", "age": 41 }));
+
+        let (status, body) = send(app(state.clone()), Method::GET, "/2", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(body["age"], 41);
+
+        let (status, body) = send(app(state.clone()), Method::POST, "/", None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(body["name"], "alice");
+
+        let (status, body) = send(app(state.clone()), Method::GET, "/users", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["users"].as_array().unwrap().len(), 2);
+        assert_eq!(body["has_more"], false);
+        assert_eq!(body["truncated"], false);
+
+        let (status, _) = send(app(state.clone()), Method::DELETE, "/users/2", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(app(state.clone()), Method::DELETE, "/users/2", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(app(state), Method::GET, "/2", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn mock_dry_runs_persist_nothing() {
+        let state = mock_state();
+
+        let alice = json!({ "name": "alice", "age": 30 });
+        let (status, _) = send(
+            app(state.clone()),
+            Method::POST,
+            "/users?dry_run=true",
+            Some(alice),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send(app(state.clone()), Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(
+            app(state),
+            Method::PATCH,
+            "/users/1",
+            Some(json!({ "age": 31 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn mock_deletes_and_finds_similar_users() {
+        let mut users = MockUsers::default();
+        for (name, age) in vec![("alice", 30), ("bob", 45), ("carol", 28), ("dave", 31)] {
+            let new_user = NewUser {
+                name: name.to_string(),
+                age: Age::try_new(age).unwrap(),
+            };
+            users.create(&new_user, None, false).await.unwrap();
+        }
+
+        assert_eq!(users.delete(UserId::Serial(4), false).await.unwrap(), 1);
+        assert_eq!(users.delete(UserId::Serial(4), false).await.unwrap(), 0);
+
+        let similar = users.similar(UserId::Serial(1), 5).await.unwrap().unwrap();
+        let names = similar
+            .iter()
+            .map(|user| user.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["carol", "bob"]);
+        assert!(users.similar(UserId::Serial(4), 5).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn postgres_only_handlers_are_not_implemented() {
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::from_env().unwrap()
+        };
+        let state = Arc::new(AppState::mock(config, MockUsers::default()));
+
+        let response = app(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/pool")
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    async fn repository(db: &TestDb) -> PostgresUserRepository {
+        let conn = db.pool.get_owned().await.unwrap();
+        PostgresUserRepository::new(conn, Duration::from_secs(30))
+    }
+
+    #[tokio::test]
+    async fn lookup_by_id_uses_prepared_statement() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .execute("insert into users (name, age) values ('alice', 30)", &[])
+            .await
+            .unwrap();
+
+        let users = repository(&db).await;
+        assert_eq!(users.conn.cached_statements(), 0);
+
+        for _ in 0..2 {
+            let user = users.get(UserId::Serial(1)).await.unwrap();
+            assert_eq!(user.name, "alice");
+        }
+        assert_eq!(users.conn.cached_statements(), 1);
+    }
+
+    #[tokio::test]
+    async fn pages_of_any_size_share_a_statement() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .execute("insert into users (name, age) values ('alice', 30)", &[])
+            .await
+            .unwrap();
+
+        let users = repository(&db).await;
+        for limit in 1..4 {
+            let page = users.list(0, limit).await.unwrap();
+            assert_eq!(page.users.len(), 1);
+            assert!(!page.has_more);
+        }
+        // the page and the count
+        assert_eq!(users.conn.cached_statements(), 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_in_name_is_replaced() {
+        let db = match test_db_with_encoding("SQL_ASCII").await {
+            Some(db) => db,
+            None => return,
+        };
+        // `\xe9` is "é" in latin-1, on its own it isn't valid UTF-8
+        db.client()
+            .await
+            .execute(
+                r"insert into users (name, age) values (E'caf\xe9', 30)",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let user = repository(&db).await.get(UserId::Serial(1)).await.unwrap();
+
+        assert_eq!(user.name, "caf\u{fffd}");
+    }
+
+    #[tokio::test]
+    async fn bigint_age_is_read() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute(
+                "alter table users alter column age type bigint; \
+                 insert into users (name, age) values ('alice', 3000000000)",
+            )
+            .await
+            .unwrap();
+
+        let user = repository(&db).await.get(UserId::Serial(1)).await.unwrap();
+
+        assert_eq!(user.age, 3_000_000_000);
+    }
+
+    #[tokio::test]
+    async fn deleted_users_are_left_out() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute("insert into users (name, age) values ('alice', 30), ('bob', 31)")
+            .await
+            .unwrap();
+        let mut users = repository(&db).await;
+
+        assert_eq!(users.delete(UserId::Serial(1), true).await.unwrap(), 1);
+        assert!(users.get(UserId::Serial(1)).await.is_ok());
+        assert_eq!(users.delete(UserId::Serial(1), false).await.unwrap(), 1);
+        assert_eq!(users.delete(UserId::Serial(1), false).await.unwrap(), 0);
+
+        // only soft-deleted, until it is purged
+        let deleted_at: Option<std::time::SystemTime> = db
+            .client()
+            .await
+            .query_one("select deleted_at from users where id = 1", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert!(deleted_at.is_some());
+
+        assert!(users.get(UserId::Serial(1)).await.is_err());
+        assert!(users.similar(UserId::Serial(1), 5).await.unwrap().is_none());
+        let page = users.list(0, 10).await.unwrap();
+        assert_eq!(page.users.len(), 1);
+        assert_eq!(page.users[0].name, "bob");
+        assert_eq!(page.total, 1);
+    }
+}
@@ -0,0 +1,73 @@
+//! An id for every request, so it can be found in our logs and, with
+//! `QUERY_REQUEST_IDS`, in the database's.
+
+use axum::http::{HeaderMap, Request};
+use std::fmt;
+use uuid::Uuid;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// The id of the request being handled.
+///
+/// Taken from the `x-request-id` header if it is a valid UUID, otherwise a new
+/// one is generated. Since it is always a UUID it is safe to put in a SQL
+/// comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestId(pub Uuid);
+
+impl RequestId {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| Uuid::parse_str(value.trim()).ok())
+            .map_or_else(|| Self(Uuid::new_v4()), Self)
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Attach a [`RequestId`] to the request for the request span and the
+/// database connection to pick up.
+pub fn assign<B>(mut request: Request<B>) -> Request<B> {
+    let id = RequestId::from_headers(request.headers());
+    request.extensions_mut().insert(id);
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn uses_incoming_uuid() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_REQUEST_ID,
+            HeaderValue::from_static("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+        );
+
+        let id = RequestId::from_headers(&headers);
+
+        assert_eq!(id.to_string(), "67e55044-10b1-426f-9247-bb680e5fe0c8");
+    }
+
+    #[test]
+    fn replaces_anything_else() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_REQUEST_ID,
+            HeaderValue::from_static("*/ drop table users; /*"),
+        );
+
+        let id = RequestId::from_headers(&headers);
+
+        assert_ne!(id.0, Uuid::nil());
+        assert!(!id.to_string().contains("drop"));
+    }
+}
@@ -0,0 +1,25 @@
+//! Security headers added to every response, for when this example is used
+//! as the starting point of a service exposed to browsers.
+
+use axum::http::{header, HeaderValue, Response};
+
+/// Add `X-Content-Type-Options`, `X-Frame-Options` and
+/// `Content-Security-Policy` to `response`.
+///
+/// Headers a handler has already set are left alone, so a route that needs
+/// a looser policy, like an HTML page loading scripts, can set its own.
+pub fn add<B>(mut response: Response<B>, content_security_policy: &HeaderValue) -> Response<B> {
+    let headers = response.headers_mut();
+
+    headers
+        .entry(header::X_CONTENT_TYPE_OPTIONS)
+        .or_insert_with(|| HeaderValue::from_static("nosniff"));
+    headers
+        .entry(header::X_FRAME_OPTIONS)
+        .or_insert_with(|| HeaderValue::from_static("DENY"));
+    headers
+        .entry(header::CONTENT_SECURITY_POLICY)
+        .or_insert_with(|| content_security_policy.clone());
+
+    response
+}
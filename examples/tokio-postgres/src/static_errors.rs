@@ -0,0 +1,182 @@
+//! Errors whose responses are always the same, rendered once when the app is
+//! built rather than for every request.
+//!
+//! These are on the hottest error paths: scanners probing for routes we
+//! don't have, and everyone at once while we are shedding load.
+
+use crate::{
+    error::ErrorMessage,
+    problem::{self, ProblemBody},
+};
+use axum::{
+    body::{box_body, BoxBody, Bytes, Full, HttpBody},
+    http::{header, HeaderValue, Response, StatusCode},
+};
+
+/// An error response with a fixed message, and its problem details already
+/// serialized for [`ProblemJson`](crate::problem::ProblemJson).
+#[derive(Debug, Clone)]
+pub struct StaticError {
+    status: StatusCode,
+    message: &'static str,
+    retry_after: Option<HeaderValue>,
+    problem: Bytes,
+}
+
+impl StaticError {
+    fn new(status: StatusCode, message: &'static str) -> Self {
+        Self {
+            status,
+            message,
+            retry_after: None,
+            problem: Bytes::from(problem::details(status, message).to_string()),
+        }
+    }
+
+    fn retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(HeaderValue::from(secs));
+        self
+    }
+
+    /// The response, sharing its body with every other one rather than
+    /// copying it.
+    pub fn response(&self) -> Response<BoxBody> {
+        let body = Bytes::from_static(self.message.as_bytes());
+        let mut response = Response::new(box_body(Full::from(body)));
+        *response.status_mut() = self.status;
+
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        if let Some(retry_after) = &self.retry_after {
+            headers.insert(header::RETRY_AFTER, retry_after.clone());
+        }
+
+        response
+            .extensions_mut()
+            .insert(ProblemBody(self.problem.clone()));
+        response
+    }
+}
+
+/// The errors we render once, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct StaticErrors {
+    pub not_found: StaticError,
+    pub method_not_allowed: StaticError,
+    /// Sent by [`LoadShed`](crate::limit::LoadShed).
+    pub overloaded: StaticError,
+}
+
+impl Default for StaticErrors {
+    fn default() -> Self {
+        Self {
+            not_found: StaticError::new(StatusCode::NOT_FOUND, "route not found"),
+            method_not_allowed: StaticError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method not allowed",
+            ),
+            overloaded: StaticError::new(StatusCode::SERVICE_UNAVAILABLE, "server is overloaded")
+                .retry_after(1),
+        }
+    }
+}
+
+/// Give the router's empty `404 Not Found` and `405 Method Not Allowed`
+/// responses a body.
+///
+/// Errors from our handlers have bodies of their own and are left alone.
+pub fn fill_in(response: Response<BoxBody>, errors: &StaticErrors) -> Response<BoxBody> {
+    let empty = response.body().size_hint().exact() == Some(0)
+        && response.extensions().get::<ErrorMessage>().is_none();
+
+    match response.status() {
+        StatusCode::NOT_FOUND if empty => errors.not_found.response(),
+        StatusCode::METHOD_NOT_ALLOWED if empty => errors.method_not_allowed.response(),
+        _ => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, test_helpers::mock_state};
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    async fn body(response: Response<BoxBody>) -> Bytes {
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn responses_share_the_bodies_rendered_up_front() {
+        let errors = StaticErrors::default();
+
+        let first = body(errors.not_found.response()).await;
+        let second = body(errors.not_found.response()).await;
+        assert_eq!(&first[..], b"route not found");
+        // the same bytes, not a copy
+        assert_eq!(first.as_ptr(), second.as_ptr());
+
+        let problem = |response: Response<BoxBody>| {
+            let ProblemBody(body) = response.extensions().get::<ProblemBody>().unwrap();
+            body.clone()
+        };
+        let first = problem(errors.not_found.response());
+        let second = problem(errors.not_found.response());
+        assert_eq!(
+            &first[..],
+            br#"{"detail":"route not found","status":404,"title":"Not Found","type":"about:blank"}"#
+        );
+        assert_eq!(first.as_ptr(), second.as_ptr());
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_and_methods_get_static_bodies() {
+        let state = mock_state();
+
+        let response = app(state.clone())
+            .oneshot(
+                Request::get("/wp-admin/setup.php")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(&body(response).await[..], b"route not found");
+
+        let response = app(state.clone())
+            .oneshot(Request::delete("/users").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(&body(response).await[..], b"method not allowed");
+
+        let response = app(state.clone())
+            .oneshot(
+                Request::get("/wp-admin/setup.php")
+                    .header(header::ACCEPT, "application/problem+json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            &body(response).await[..],
+            br#"{"detail":"route not found","status":404,"title":"Not Found","type":"about:blank"}"#
+        );
+
+        // our own not found errors keep their message
+        let response = app(state)
+            .oneshot(
+                Request::get("/users/by-name/nobody")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(&body(response).await[..], b"user not found");
+    }
+}
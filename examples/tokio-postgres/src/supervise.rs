@@ -0,0 +1,126 @@
+//! Restarts background jobs that panic, rather than letting them die quietly
+//! and the feature they provide stop working.
+
+use std::{
+    any::Any,
+    future::Future,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+/// How long to wait before the first restart, doubling for each one after
+/// up to the maximum.
+const FIRST_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// Run the job `start` returns on a task of its own until it finishes,
+/// starting it again whenever it panics.
+///
+/// Panics are logged at `error` with the job's `name`. Restarts back off up
+/// to `max_delay`, starting over once the job stayed up for longer than that.
+/// We stop restarting once `shutdown` changes.
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    max_delay: Duration,
+    mut shutdown: watch::Receiver<bool>,
+    mut start: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut delay = FIRST_RESTART_DELAY.min(max_delay);
+
+    loop {
+        let started = Instant::now();
+        let err = match tokio::spawn(start()).await {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        // cancelled, which only happens when the runtime shuts down
+        if !err.is_panic() {
+            return;
+        }
+
+        if started.elapsed() > max_delay {
+            delay = FIRST_RESTART_DELAY.min(max_delay);
+        }
+        tracing::error!(
+            task = name,
+            panic = %panic_message(&*err.into_panic()),
+            ?delay,
+            "background task panicked, restarting"
+        );
+
+        if tokio::time::timeout(delay, shutdown.changed())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        delay = (delay * 2).min(max_delay);
+    }
+}
+
+/// The message `panic!` was called with, if it was given one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::CapturedLogs;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn panicking_jobs_are_restarted() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let job = supervise("flaky", Duration::from_millis(10), shutdown_rx, || {
+            let runs = runs.clone();
+            async move {
+                if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("lost my place");
+                }
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(1), job)
+            .await
+            .expect("job wasn't restarted");
+
+        // it got to finish the second time
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        let logs = logs.contents();
+        assert!(logs.contains("background task panicked"), "{}", logs);
+        assert!(logs.contains("task=\"flaky\""), "{}", logs);
+        assert!(logs.contains("panic=lost my place"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn restarts_stop_on_shutdown() {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let job = tokio::spawn(supervise(
+            "broken",
+            Duration::from_secs(60),
+            shutdown_rx,
+            || async { panic!("always") },
+        ));
+        shutdown.send(true).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), job)
+            .await
+            .expect("job didn't stop")
+            .unwrap();
+    }
+}
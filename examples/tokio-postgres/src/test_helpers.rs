@@ -0,0 +1,269 @@
+//! Helpers shared by the tests in this crate.
+//!
+//! Tests that need Postgres use [`test_db`] and are skipped unless
+//! `TEST_DATABASE_URL` is set, for example:
+//!
+//! ```not_rust
+//! TEST_DATABASE_URL="host=localhost user=postgres" cargo test -p example-tokio-postgres
+//! ```
+
+use crate::{
+    db::{ConnectionPool, Manager},
+    repository::MockUsers,
+    AppState, Config, SharedState,
+};
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    routing::BoxRoute,
+    Router,
+};
+use bb8::Pool;
+use serde_json::Value;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio_postgres::NoTls;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+const SCHEMA: &str = include_str!("../schema.sql");
+const SCHEMA_UUID: &str = include_str!("../schema-uuid.sql");
+
+/// A schema, with our tables, created for a single test and dropped again
+/// afterwards.
+pub struct TestDb {
+    pub pool: ConnectionPool,
+    config: tokio_postgres::Config,
+    schema: String,
+    /// Set if the test got a database of its own, rather than just a schema.
+    database: Option<(tokio_postgres::Config, String)>,
+}
+
+/// Create a [`TestDb`], or return `None` if `TEST_DATABASE_URL` isn't set in
+/// which case the test should be skipped.
+pub async fn test_db() -> Option<TestDb> {
+    let config = test_database_config()?;
+    Some(TestDb::new(config, None, SCHEMA).await)
+}
+
+/// Like [`test_db`] but with the tables for `UUID_IDS=true`.
+pub async fn test_db_with_uuid_ids() -> Option<TestDb> {
+    let config = test_database_config()?;
+    Some(TestDb::new(config, None, SCHEMA_UUID).await)
+}
+
+/// Like [`test_db`] but in a new database with the given encoding, for tests
+/// that need data a `UTF8` database would reject.
+pub async fn test_db_with_encoding(encoding: &str) -> Option<TestDb> {
+    let admin = test_database_config()?;
+    let name = format!("test_{}", Uuid::new_v4().to_simple());
+
+    connect(&admin)
+        .await
+        .batch_execute(&format!(
+            "create database {} encoding '{}' lc_collate 'C' lc_ctype 'C' template template0",
+            name, encoding
+        ))
+        .await
+        .unwrap();
+
+    let mut config = admin.clone();
+    config.dbname(&name);
+    Some(TestDb::new(config, Some((admin, name)), SCHEMA).await)
+}
+
+fn test_database_config() -> Option<tokio_postgres::Config> {
+    match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => Some(url.parse().unwrap()),
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL isn't set, skipping test");
+            None
+        }
+    }
+}
+
+/// State whose pool never connects until a connection is checked out, so
+/// it can be used in tests that don't need a database.
+pub fn test_state() -> SharedState {
+    test_state_with(Config::from_env().unwrap())
+}
+
+pub fn test_state_with(config: Config) -> SharedState {
+    let manager = Manager::new(config.database_url.parse().unwrap());
+    let pool = Pool::builder()
+        .max_size(config.pool_max_size)
+        .connection_timeout(config.pool_timeout)
+        .build_unchecked(manager);
+
+    Arc::new(AppState::new(pool, config))
+}
+
+/// State keeping users in memory, for testing handlers written against
+/// [`UserRepository`](crate::repository::UserRepository) without a database.
+pub fn mock_state() -> SharedState {
+    Arc::new(AppState::mock(
+        Config::from_env().unwrap(),
+        MockUsers::default(),
+    ))
+}
+
+/// Send a request with an optional JSON body to `app`, returning the status
+/// and the JSON body of the response, or `null` if it isn't JSON.
+pub async fn send(
+    app: Router<BoxRoute>,
+    method: Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+
+    let response = app.oneshot(request.body(body).unwrap()).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+impl TestDb {
+    async fn new(
+        config: tokio_postgres::Config,
+        database: Option<(tokio_postgres::Config, String)>,
+        tables: &str,
+    ) -> Self {
+        let schema = format!("test_{}", Uuid::new_v4().to_simple());
+
+        connect(&config)
+            .await
+            .batch_execute(&format!(
+                "create schema {schema}; set search_path to {schema}; {tables}",
+                schema = schema,
+                tables = tables,
+            ))
+            .await
+            .unwrap();
+
+        let manager = Manager::new(config.clone()).search_path(&schema);
+        let pool = Pool::builder().build(manager).await.unwrap();
+
+        Self {
+            pool,
+            config,
+            schema,
+            database,
+        }
+    }
+
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    /// State using this database.
+    pub fn state(&self) -> SharedState {
+        Arc::new(AppState::new(
+            self.pool.clone(),
+            Config::from_env().unwrap(),
+        ))
+    }
+
+    /// State using this database with `config`, and a new pool sized to
+    /// match it whose connections are counted in the state's
+    /// `connection_stats`.
+    pub async fn state_with(&self, config: Config) -> SharedState {
+        let state = AppState::starting(config);
+        let manager = Manager::new(self.config.clone())
+            .search_path(&self.schema)
+            .statement_timeout(state.config.statement_timeout)
+            .stats(state.connection_stats.clone())
+            .breaker(state.breaker.clone());
+        let pool = Pool::builder()
+            .max_size(state.config.pool_max_size)
+            .connection_timeout(state.config.pool_timeout)
+            .build(manager)
+            .await
+            .unwrap();
+
+        state.set_pool(pool);
+        Arc::new(state)
+    }
+
+    /// A client outside of the pool, for setting up and inspecting data.
+    pub async fn client(&self) -> tokio_postgres::Client {
+        let client = connect(&self.config).await;
+        client
+            .batch_execute(&format!("set search_path to {}", self.schema))
+            .await
+            .unwrap();
+        client
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let (config, statement) = match self.database.clone() {
+            Some((admin, name)) => (admin, format!("drop database {} with (force)", name)),
+            None => (
+                self.config.clone(),
+                format!("drop schema {} cascade", self.schema),
+            ),
+        };
+
+        // we might be dropped inside a runtime so clean up on another thread
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    connect(&config)
+                        .await
+                        .batch_execute(&statement)
+                        .await
+                        .unwrap();
+                })
+        })
+        .join()
+        .unwrap();
+    }
+}
+
+async fn connect(config: &tokio_postgres::Config) -> tokio_postgres::Client {
+    let (client, connection) = config.connect(NoTls).await.unwrap();
+    tokio::spawn(connection);
+    client
+}
+
+/// Collects everything written by a `tracing_subscriber::fmt` subscriber so
+/// tests can make assertions about log lines.
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub fn subscriber(&self) -> impl tracing::Subscriber {
+        let logs = self.clone();
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || logs.clone())
+            .finish()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//! Propagation of [W3C trace context](https://www.w3.org/TR/trace-context/)
+//! so this service can take part in distributed traces.
+
+use crate::request_id::RequestId;
+use axum::http::{HeaderMap, Request};
+use tracing::Span;
+use uuid::Uuid;
+
+pub const TRACEPARENT: &str = "traceparent";
+
+/// The trace a request belongs to.
+///
+/// `trace_id` and `parent_id` come from the incoming `traceparent` header if
+/// there was a valid one, otherwise a new trace is started. `span_id`
+/// identifies this service's part of the trace.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub parent_id: Option<String>,
+    pub span_id: String,
+}
+
+impl TraceContext {
+    /// Continue the trace from the request headers or start a new one.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::parse)
+            .unwrap_or_else(Self::new_root)
+    }
+
+    fn new_root() -> Self {
+        Self {
+            trace_id: Uuid::new_v4().to_simple().to_string(),
+            parent_id: None,
+            span_id: new_span_id(),
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        // future versions may append fields but version 00 has exactly four
+        if version == "ff" || !is_hex(version, 2) || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+
+        if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+
+        if is_zero(trace_id) || is_zero(parent_id) {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            parent_id: Some(parent_id.to_string()),
+            span_id: new_span_id(),
+        })
+    }
+}
+
+fn new_span_id() -> String {
+    Uuid::new_v4().to_simple().to_string()[..16].to_string()
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(value: &str) -> bool {
+    value.bytes().all(|b| b == b'0')
+}
+
+/// Attach a [`TraceContext`] to the request so it can be picked up by
+/// [`make_span`].
+pub fn propagate<B>(mut request: Request<B>) -> Request<B> {
+    let trace = TraceContext::from_headers(request.headers());
+    request.extensions_mut().insert(trace);
+    request
+}
+
+/// Create the span for a request, including the trace ids so they show up on
+/// every log line emitted while handling it.
+pub fn make_span<B>(request: &Request<B>) -> Span {
+    let trace = request
+        .extensions()
+        .get::<TraceContext>()
+        .cloned()
+        .unwrap_or_else(|| TraceContext::from_headers(request.headers()));
+
+    let span = tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        trace_id = %trace.trace_id,
+        span_id = %trace.span_id,
+        parent_id = tracing::field::Empty,
+        request_id = tracing::field::Empty,
+        // recorded by the handlers that use the database, to tell waiting
+        // for a connection apart from slow queries
+        acquire_ms = tracing::field::Empty,
+        query_ms = tracing::field::Empty,
+    );
+
+    if let Some(parent_id) = &trace.parent_id {
+        span.record("parent_id", tracing::field::display(parent_id));
+    }
+    if let Some(request_id) = request.extensions().get::<RequestId>() {
+        span.record("request_id", tracing::field::display(request_id));
+    }
+
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn parses_valid_traceparent() {
+        let trace =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+
+        assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.parent_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_ne!(trace.span_id, "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn starts_new_trace_for_invalid_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT,
+            HeaderValue::from_static("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+        );
+
+        let trace = TraceContext::from_headers(&headers);
+
+        assert_eq!(trace.trace_id.len(), 32);
+        assert_ne!(trace.trace_id, "00000000000000000000000000000000");
+        assert!(trace.parent_id.is_none());
+    }
+}
@@ -0,0 +1,106 @@
+//! Serving on a Unix domain socket rather than TCP, with `BIND_UDS`.
+//!
+//! Useful behind a proxy or sidecar on the same host: there is no TCP hop,
+//! and who may connect is down to the permissions of the socket file.
+
+use hyper::server::accept::{self, Accept};
+use std::{
+    io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Listen on a socket at `path`.
+///
+/// A socket left at `path`, say by a previous run that crashed, is replaced.
+/// Any other kind of file is left alone, and binding fails.
+pub fn bind(
+    path: &Path,
+) -> io::Result<(
+    impl Accept<Conn = UnixStream, Error = io::Error>,
+    SocketFile,
+)> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let incoming = accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    });
+
+    Ok((incoming, SocketFile(path.to_owned())))
+}
+
+/// The file of a socket we listen on, removed when this is dropped so it
+/// doesn't outlive us.
+#[derive(Debug)]
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            if err.kind() != io::ErrorKind::NotFound {
+                tracing::warn!(%err, path = %self.0.display(), "failed to remove socket file");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{run, Config};
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+        sync::oneshot,
+    };
+
+    #[tokio::test]
+    async fn requests_can_be_served_over_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!(
+            "example-tokio-postgres-{}.sock",
+            std::process::id()
+        ));
+        let config = Config {
+            bind_uds: Some(path.clone()),
+            ..Config::from_env().unwrap()
+        };
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run(
+            config,
+            async {
+                stopped.await.ok();
+            },
+            || async {},
+        ));
+
+        let mut stream = None;
+        for _ in 0..100 {
+            if let Ok(connected) = UnixStream::connect(&path).await {
+                stream = Some(connected);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut stream = stream.expect("server never listened on the socket");
+
+        stream
+            .write_all(b"GET /live HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("ok"), "{}", response);
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,206 @@
+//! User ids, which are integers unless `UUID_IDS` is set.
+//!
+//! With `UUID_IDS=true` the `users` table is expected to have a `uuid`
+//! primary key, as created by `schema-uuid.sql`, so ids don't give away how
+//! many users there are or in which order they signed up.
+
+use crate::{error::AppError, SharedState};
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, Path, RequestParts},
+    http::StatusCode,
+};
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{error::Error, fmt};
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use uuid::Uuid;
+
+/// The id of a user, either from a `serial` or a `uuid` column.
+///
+/// Serialized as a number or a string respectively. As a parameter it can be
+/// compared against either kind of column, though Postgres only accepts the
+/// kind the column actually is.
+///
+/// As an extractor it parses the `id` path parameter, which must be the kind
+/// of id `UUID_IDS` says we use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UserId {
+    Serial(i32),
+    Uuid(Uuid),
+}
+
+impl UserId {
+    pub fn parse(id: &str, uuid_ids: bool) -> Result<Self, AppError> {
+        if uuid_ids {
+            Uuid::parse_str(id)
+                .map(Self::Uuid)
+                .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, "id must be a UUID"))
+        } else {
+            id.parse()
+                .map(Self::Serial)
+                .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, "id must be an integer"))
+        }
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Serial(id) => id.fmt(f),
+            Self::Uuid(id) => id.fmt(f),
+        }
+    }
+}
+
+impl Serialize for UserId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Self::Serial(id) => serializer.serialize_i32(*id),
+            Self::Uuid(id) => serializer.collect_str(id),
+        }
+    }
+}
+
+impl<'a> FromSql<'a> for UserId {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        if *ty == Type::UUID {
+            Uuid::from_sql(ty, raw).map(Self::Uuid)
+        } else {
+            i32::from_sql(ty, raw).map(Self::Serial)
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i32 as FromSql>::accepts(ty) || <Uuid as FromSql>::accepts(ty)
+    }
+}
+
+impl ToSql for UserId {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            Self::Serial(id) => id.to_sql(ty, out),
+            Self::Uuid(id) => id.to_sql(ty, out),
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i32 as ToSql>::accepts(ty) || <Uuid as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for UserId
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(AppError::internal)?;
+        // only fails if the route has no `id`, which is our mistake
+        let Path(path) = Path::<UserIdPath>::from_request(req)
+            .await
+            .map_err(AppError::internal)?;
+
+        Self::parse(&path.id, state.config.uuid_ids)
+    }
+}
+
+/// The path parameters of routes for a single user, like `/users/:id`.
+///
+/// The id is kept as a string since which kind it is depends on `UUID_IDS`.
+#[derive(Debug, Deserialize)]
+struct UserIdPath {
+    id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app,
+        repository::MockUsers,
+        test_helpers::{send, test_db_with_uuid_ids},
+        AppState, Config, SharedState,
+    };
+    use axum::http::Method;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn ids_must_be_the_configured_kind() {
+        assert_eq!(UserId::parse("42", false).unwrap(), UserId::Serial(42));
+        assert!(UserId::parse("67e55044-10b1-426f-9247-bb680e5fe0c8", false).is_err());
+
+        let id = UserId::parse("67e55044-10b1-426f-9247-bb680e5fe0c8", true).unwrap();
+        assert_eq!(
+            serde_json::to_value(id).unwrap(),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+        assert_eq!(
+            UserId::parse("42", true).unwrap_err().message(),
+            "id must be a UUID"
+        );
+    }
+
+    fn uuid_config() -> Config {
+        Config {
+            uuid_ids: true,
+            ..Config::from_env().unwrap()
+        }
+    }
+
+    /// Create a user, then read and update it by the id it was given.
+    async fn round_trip(state: SharedState) {
+        let alice = json!({ "name": "alice", "age": 30 });
+        let (status, created) = send(app(state.clone()), Method::POST, "/users", Some(alice)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = created["id"].as_str().unwrap();
+        assert!(Uuid::parse_str(id).is_ok(), "{}", id);
+
+        let (status, body) = send(app(state.clone()), Method::GET, &format!("/{}", id), None).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(body, created);
+
+        let (status, body) = send(
+            app(state.clone()),
+            Method::PATCH,
+            &format!("/users/{}", id),
+            Some(json!({ "age": 31 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "id": id, "name": "alice", "age": 31 }));
+
+        let (status, _) = send(app(state), Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn uuid_ids_round_trip() {
+        let db = match test_db_with_uuid_ids().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        round_trip(db.state_with(uuid_config()).await).await;
+    }
+
+    #[tokio::test]
+    async fn uuid_ids_round_trip_with_mock_users() {
+        let state = Arc::new(AppState::mock(uuid_config(), MockUsers::new(true)));
+        round_trip(state).await;
+    }
+}
@@ -0,0 +1,2074 @@
+//! Handlers for listing, creating, and updating users.
+
+use crate::{
+    admin::Admin,
+    age::Age,
+    error::AppError,
+    naming,
+    repository::{Updated, Upserted, UserRepository},
+    user_id::UserId,
+    Config, ConnContext, SharedState, User, Users,
+};
+use axum::{
+    async_trait,
+    body::{box_body, Body, BoxBody, Bytes, HttpBody},
+    extract::{Extension, FromRequest, Path, Query, RawBody, RequestParts},
+    http::{header, HeaderMap, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    convert::{Infallible, TryFrom},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    pub name: String,
+    pub age: Age,
+}
+
+impl Fields for NewUser {
+    const FIELDS: &'static [&'static str] = &["name", "age"];
+}
+
+impl NewUser {
+    fn validate(&self) -> Result<(), AppError> {
+        validate_name(&self.name)
+    }
+}
+
+/// The fields to change with `PATCH /users/:id`. Missing fields are left as
+/// they are.
+#[derive(Debug, Deserialize)]
+pub struct UpdateUser {
+    pub name: Option<String>,
+    pub age: Option<Age>,
+}
+
+impl Fields for UpdateUser {
+    const FIELDS: &'static [&'static str] = &["name", "age"];
+}
+
+impl UpdateUser {
+    fn validate(&self) -> Result<(), AppError> {
+        if let Some(name) = &self.name {
+            validate_name(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The body of `PUT /users/by-name/:name`, which has the name in its path.
+#[derive(Debug, Deserialize)]
+pub struct UpsertUser {
+    pub age: Age,
+}
+
+impl Fields for UpsertUser {
+    const FIELDS: &'static [&'static str] = &["age"];
+}
+
+/// The body of `POST /users/bulk-update`.
+#[derive(Debug, Deserialize)]
+pub struct BulkUpdate {
+    /// Only update users at most this old.
+    pub max_age: Option<i64>,
+    /// Update every user. Required if there is no filter, so a forgotten filter
+    /// doesn't update the whole table.
+    #[serde(default)]
+    pub all: bool,
+    pub name: Option<String>,
+    pub age: Option<Age>,
+    /// Added to the age of every user updated, can be negative.
+    pub increment_age: Option<i64>,
+}
+
+impl Fields for BulkUpdate {
+    const FIELDS: &'static [&'static str] = &["max_age", "all", "name", "age", "increment_age"];
+}
+
+impl BulkUpdate {
+    fn validate(&self) -> Result<(), AppError> {
+        if self.max_age.is_none() && !self.all {
+            return Err(AppError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "a filter is required, or `all: true` to update every user",
+            ));
+        }
+        if self.name.is_none() && self.age.is_none() && self.increment_age.is_none() {
+            return Err(AppError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "nothing to update",
+            ));
+        }
+        if self.age.is_some() && self.increment_age.is_some() {
+            return Err(AppError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "only one of `age` and `increment_age` may be set",
+            ));
+        }
+        if let Some(name) = &self.name {
+            validate_name(name)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), AppError> {
+    if name.trim().is_empty() {
+        return Err(AppError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "name must not be empty",
+        ));
+    }
+    Ok(())
+}
+
+/// The fields a request body may contain.
+pub trait Fields {
+    const FIELDS: &'static [&'static str];
+}
+
+/// Like [`Json`] but, if `REJECT_UNKNOWN_FIELDS` is set, rejects bodies with
+/// fields that aren't in [`Fields::FIELDS`] with `400 Bad Request`.
+///
+/// This catches typos like `{"nmae": "x"}` that serde would otherwise ignore.
+/// It is off by default since it means clients can't send fields a newer
+/// version of the API might understand.
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for JsonBody<T>
+where
+    T: DeserializeOwned + Fields,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<tower::BoxError>,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(AppError::internal)?;
+
+        check_json_content_type(
+            req.headers()
+                .and_then(|headers| headers.get(header::CONTENT_TYPE)),
+        )?;
+
+        // read the bytes ourselves rather than with `Json`, so a body that
+        // isn't UTF-8 gets a better error than serde's
+        let bytes = Bytes::from_request(req)
+            .await
+            .map_err(|rejection| AppError::new(StatusCode::BAD_REQUEST, rejection.to_string()))?;
+        if std::str::from_utf8(&bytes).is_err() {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "body is not valid UTF-8 JSON",
+            ));
+        }
+        let body = serde_json::from_slice::<Value>(&bytes).map_err(|err| {
+            AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Failed to parse the request body as JSON: {}", err),
+            )
+        })?;
+
+        Ok(Self(from_json(body, &state.config)?))
+    }
+}
+
+/// Check that `content_type` is JSON, like [`Json`] does, and that it's in
+/// UTF-8 if it says.
+///
+/// JSON must be UTF-8, so clients declaring another charset get `415
+/// Unsupported Media Type` rather than us guessing at what they sent.
+fn check_json_content_type(content_type: Option<&HeaderValue>) -> Result<(), AppError> {
+    let mut parts = content_type
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(';');
+
+    let essence = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+    let is_json = essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"));
+    if !is_json {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "Expected request with `Content-Type: application/json`",
+        ));
+    }
+
+    for param in parts {
+        let mut param = param.splitn(2, '=');
+        let (name, value) = match (param.next(), param.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => continue,
+        };
+        let charset = value.trim().trim_matches('"');
+        if name.trim().eq_ignore_ascii_case("charset")
+            && !charset.eq_ignore_ascii_case("utf-8")
+            && !charset.eq_ignore_ascii_case("utf8")
+        {
+            return Err(AppError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported charset `{}`, bodies must be UTF-8", charset),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Like [`Query`] but rejecting query strings that don't deserialize as `T`
+/// with a `400 Bad Request` [`AppError`], so they look like our other errors.
+pub struct QueryParams<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for QueryParams<T>
+where
+    T: DeserializeOwned,
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<T>::from_request(req)
+            .await
+            .map_err(|rejection| AppError::new(StatusCode::BAD_REQUEST, rejection.to_string()))?;
+
+        Ok(Self(params))
+    }
+}
+
+/// Deserialize `T` from `body`, first checking it only has fields in
+/// [`Fields::FIELDS`] if `REJECT_UNKNOWN_FIELDS` is set.
+///
+/// With `JSON_CAMEL_CASE` the fields are expected in camelCase instead.
+fn from_json<T>(body: Value, config: &Config) -> Result<T, AppError>
+where
+    T: DeserializeOwned + Fields,
+{
+    let fields = if config.json_camel_case {
+        T::FIELDS
+            .iter()
+            .map(|field| naming::camel_case(field))
+            .collect()
+    } else {
+        T::FIELDS
+            .iter()
+            .map(|field| field.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    if config.reject_unknown_fields {
+        if let Some(field) = body
+            .as_object()
+            .and_then(|body| body.keys().find(|key| !fields.contains(key)))
+        {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "unknown field `{}`, expected one of {}",
+                    field,
+                    fields.join(", ")
+                ),
+            ));
+        }
+    }
+
+    let body = if config.json_camel_case {
+        naming::from_camel_case(body, T::FIELDS)
+    } else {
+        body
+    };
+    serde_json::from_value(body)
+        .map_err(|err| AppError::new(StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserList {
+    users: Vec<User>,
+    /// Set if there are more users after these, which the `Link` header says
+    /// how to get.
+    has_more: bool,
+    /// Set if `MAX_ROWS` made this page smaller than asked for, and there
+    /// were more users that would have been on it.
+    truncated: bool,
+    /// How many users there are across all pages.
+    total: i64,
+}
+
+/// Query parameters accepted by `GET /users`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ListParams {
+    /// How many users to return, see [`page_limit`].
+    limit: Option<usize>,
+    /// How many users to skip.
+    #[serde(default)]
+    offset: usize,
+}
+
+/// Handler for `GET /users`.
+///
+/// Pages through users with `?limit=` and `?offset=`, linking to the next and
+/// previous pages in a `Link` header.
+pub async fn list_users(
+    Users(users): Users,
+    Extension(state): Extension<SharedState>,
+    QueryParams(params): QueryParams<ListParams>,
+) -> Result<(HeaderMap, Json<UserList>), AppError> {
+    let limit = page_limit(params.limit, &state.config)?;
+    // offsets are `bigint` in Postgres
+    if i64::try_from(params.offset).is_err() {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("offset must be at most {}", i64::MAX),
+        ));
+    }
+    let page = users.list(params.offset, limit).await?;
+
+    let wanted = params.limit.unwrap_or(state.config.default_page_limit);
+    let truncated = page.has_more && wanted > limit;
+    if truncated {
+        tracing::warn!(
+            max_rows = state.config.max_rows,
+            wanted,
+            "page hit the row cap, results truncated"
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    if let Some(links) = page_links(params.offset, limit, page.has_more) {
+        headers.insert(header::LINK, links);
+    }
+
+    Ok((
+        headers,
+        Json(UserList {
+            users: page.users,
+            has_more: page.has_more,
+            truncated,
+            total: page.total,
+        }),
+    ))
+}
+
+/// The `Link` header, as in RFC 8288, for the page of `GET /users` at `offset`.
+///
+/// There is a previous page unless this is the first one, and a next page if
+/// users were left out of this one.
+fn page_links(offset: usize, limit: usize, has_more: bool) -> Option<HeaderValue> {
+    let link = |offset: usize, rel: &str| {
+        format!(
+            "</users?limit={}&offset={}>; rel=\"{}\"",
+            limit, offset, rel
+        )
+    };
+
+    let mut links = Vec::new();
+    if has_more {
+        links.push(link(offset.saturating_add(limit), "next"));
+    }
+    if offset > 0 {
+        links.push(link(offset.saturating_sub(limit), "prev"));
+    }
+
+    if links.is_empty() {
+        None
+    } else {
+        Some(HeaderValue::from_str(&links.join(", ")).unwrap())
+    }
+}
+
+/// How many rows to return from an endpoint returning many, for the `limit`
+/// the client asked for.
+///
+/// Every such endpoint uses this so none of them can return a whole table
+/// just because the client didn't say how much it wanted. Without a limit
+/// that is `DEFAULT_PAGE_LIMIT`, and never more than `MAX_ROWS`.
+fn page_limit(limit: Option<usize>, config: &Config) -> Result<usize, AppError> {
+    let limit = limit
+        .unwrap_or(config.default_page_limit)
+        .min(config.max_rows);
+    if limit == 0 {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "limit must be at least 1",
+        ));
+    }
+    Ok(limit)
+}
+
+/// Query parameters accepted by `GET /users/:id/similar`.
+#[derive(Debug, Default, Deserialize)]
+pub struct SimilarParams {
+    /// How many users to return, see [`page_limit`].
+    limit: Option<usize>,
+}
+
+/// Handler for `GET /users/:id/similar`.
+///
+/// Returns the other users closest in age to the user, closest first.
+pub async fn similar_users(
+    Users(users): Users,
+    Extension(state): Extension<SharedState>,
+    id: UserId,
+    QueryParams(params): QueryParams<SimilarParams>,
+) -> Result<Json<Vec<User>>, AppError> {
+    let limit = page_limit(params.limit, &state.config)?;
+    let users = users
+        .similar(id, limit as i64)
+        .await?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "user not found"))?;
+
+    Ok(Json(users))
+}
+
+/// The body of `POST /users/lookup`.
+#[derive(Debug, Deserialize)]
+pub struct LookupUsers {
+    /// Numbers or strings, whichever kind `UUID_IDS` says we use.
+    ids: Vec<Value>,
+}
+
+impl Fields for LookupUsers {
+    const FIELDS: &'static [&'static str] = &["ids"];
+}
+
+/// Handler for `POST /users/lookup`.
+///
+/// Returns the users with the ids in the body, in the order they were
+/// given. Repeated ids, and ids without a user, are left out. At most
+/// `MAX_LOOKUP_IDS` ids can be looked up at once.
+pub async fn lookup_users(
+    Users(users): Users,
+    Extension(state): Extension<SharedState>,
+    JsonBody(lookup): JsonBody<LookupUsers>,
+) -> Result<Json<Vec<User>>, AppError> {
+    let max = state.config.max_lookup_ids;
+    if lookup.ids.len() > max {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("at most {} ids can be looked up at once", max),
+        ));
+    }
+
+    let mut seen = HashSet::with_capacity(lookup.ids.len());
+    let mut ids = Vec::with_capacity(lookup.ids.len());
+    for id in &lookup.ids {
+        let id = match id {
+            Value::Number(id) => id.to_string(),
+            Value::String(id) => id.clone(),
+            _ => {
+                return Err(AppError::new(
+                    StatusCode::BAD_REQUEST,
+                    "ids must be numbers or strings",
+                ))
+            }
+        };
+        let id = UserId::parse(&id, state.config.uuid_ids)?;
+        if seen.insert(id) {
+            ids.push(id);
+        }
+    }
+
+    let users = users.get_many(&ids, state.config.lookup_chunk_size).await?;
+    Ok(Json(users))
+}
+
+/// Handler for `GET /users/by-name/:name`.
+///
+/// Only matches the whole name, including case. Since names aren't unique
+/// this is `409 Conflict` if several users have the name.
+pub async fn user_by_name(
+    Users(users): Users,
+    Path(name): Path<String>,
+) -> Result<Json<User>, AppError> {
+    Ok(Json(users.get_by_name(&name).await?))
+}
+
+/// Handler for `PUT /users/by-name/:name`.
+///
+/// Sets the age of the user with the name, or creates them if there is no
+/// such user, which is `201 Created` rather than `200 OK`. Like `GET` this is
+/// `409 Conflict` if several users have the name.
+pub async fn upsert_user_by_name(
+    Users(mut users): Users,
+    Extension(state): Extension<SharedState>,
+    Path(name): Path<String>,
+    QueryParams(params): QueryParams<MutationParams>,
+    JsonBody(upsert): JsonBody<UpsertUser>,
+) -> Result<MutationResponse, AppError> {
+    validate_name(&name)?;
+
+    let Upserted { user, created } = users
+        .upsert_by_name(&name, upsert.age, params.dry_run)
+        .await?;
+
+    if params.dry_run {
+        return Ok(mutation_response(StatusCode::OK, true, user));
+    }
+
+    state.cache.insert(user.clone());
+    if !created {
+        return Ok(mutation_response(StatusCode::OK, false, user));
+    }
+
+    let (status, mut headers, user) = mutation_response(StatusCode::CREATED, false, user);
+    let location = HeaderValue::from_str(&format!("/{}", user.id)).unwrap();
+    headers.insert(header::LOCATION, location);
+    Ok((status, headers, user))
+}
+
+/// Query parameters accepted by all mutating endpoints.
+#[derive(Debug, Default, Deserialize)]
+pub struct MutationParams {
+    /// Validate and run the change inside a transaction that is then rolled
+    /// back, so clients can see what would have happened without persisting
+    /// anything.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+type MutationResponse = (StatusCode, HeaderMap, Json<User>);
+
+/// Handler for `POST /users`.
+///
+/// Responds with the created user, or with just its `Location` and an empty
+/// body if the client sends `Prefer: return=minimal` (RFC 7240).
+///
+/// With an `Idempotency-Key` header the user is created only once per key,
+/// sending the same key again responds with the user created the first time.
+/// That also makes it safe to retry the insert if we lose the connection
+/// while it runs, which we do once. Without a key we can't tell whether such
+/// an insert went through, so it isn't retried and the client gets the error.
+pub async fn create_user(
+    Extension(state): Extension<SharedState>,
+    context: ConnContext,
+    QueryParams(params): QueryParams<MutationParams>,
+    preference: ReturnPreference,
+    IdempotencyKey(key): IdempotencyKey,
+    JsonBody(new_user): JsonBody<NewUser>,
+) -> Result<Response<BoxBody>, AppError> {
+    new_user.validate()?;
+
+    let key = key.as_deref();
+
+    let result = state
+        .users(context)
+        .await?
+        .create(&new_user, key, params.dry_run)
+        .await;
+    let user = match result {
+        Err(err) if err.is_connection_lost() && key.is_some() => {
+            tracing::warn!(err = %err.message(), "lost the connection creating a user, retrying");
+            state
+                .users(context)
+                .await?
+                .create(&new_user, key, params.dry_run)
+                .await?
+        }
+        result => result?,
+    };
+
+    let (status, mut headers, Json(user)) =
+        mutation_response(StatusCode::CREATED, params.dry_run, user);
+
+    // a dry run didn't create anything so there is nowhere to point to. Users
+    // are fetched with `GET /:id`
+    if !params.dry_run {
+        let location = HeaderValue::from_str(&format!("/{}", user.id)).unwrap();
+        headers.insert(header::LOCATION, location);
+    }
+
+    if let ReturnPreference::Minimal = preference {
+        headers.insert(
+            "preference-applied",
+            HeaderValue::from_static("return=minimal"),
+        );
+        Ok((status, headers, ()).into_response().map(box_body))
+    } else {
+        Ok((status, headers, Json(user)).into_response().map(box_body))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkUpdated {
+    updated: u64,
+}
+
+/// Handler for `POST /users/bulk-update`, which requires `ADMIN_TOKEN`.
+///
+/// Applies the changes to every user matching the filter with a single
+/// statement, and responds with how many were updated, as [`Affected`] with
+/// `Prefer: return=count`.
+pub async fn bulk_update_users(
+    _: Admin,
+    Users(mut users): Users,
+    Extension(state): Extension<SharedState>,
+    QueryParams(params): QueryParams<MutationParams>,
+    preference: ReturnPreference,
+    JsonBody(update): JsonBody<BulkUpdate>,
+) -> Result<Response<BoxBody>, AppError> {
+    update.validate()?;
+
+    let updated = users.bulk_update(&update, params.dry_run).await?;
+
+    let mut headers = HeaderMap::new();
+    if params.dry_run {
+        headers.insert("x-dry-run", HeaderValue::from_static("true"));
+    } else {
+        // we don't know which users changed
+        state.cache.clear();
+    }
+
+    if let ReturnPreference::Count = preference {
+        return Ok(affected_response(headers, updated));
+    }
+    Ok((headers, Json(BulkUpdated { updated }))
+        .into_response()
+        .map(box_body))
+}
+
+/// The body of responses to updates and deletes for clients that send
+/// `Prefer: return=count`.
+#[derive(Debug, Serialize)]
+pub struct Affected {
+    /// How many users were changed, or would have been by a dry run.
+    affected: u64,
+}
+
+fn affected_response(mut headers: HeaderMap, affected: u64) -> Response<BoxBody> {
+    headers.insert(
+        "preference-applied",
+        HeaderValue::from_static("return=count"),
+    );
+    (StatusCode::OK, headers, Json(Affected { affected }))
+        .into_response()
+        .map(box_body)
+}
+
+/// Query parameters accepted by `POST /users/import`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportParams {
+    /// Stop at the first invalid line rather than skipping it.
+    #[serde(default)]
+    pub stop_on_error: bool,
+    /// Like [`MutationParams::dry_run`].
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// How many users are inserted per transaction when importing.
+const IMPORT_BATCH_SIZE: usize = 100;
+
+/// Longer lines are reported as errors, so a client can't make us buffer
+/// an arbitrary amount while looking for the end of a line.
+const MAX_IMPORT_LINE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Serialize)]
+pub struct ImportError {
+    /// Counting from 1.
+    line: usize,
+    message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    imported: u64,
+    errors: Vec<ImportError>,
+    /// Set if we stopped at an error, because of `stop_on_error`, before
+    /// reading the whole body.
+    stopped: bool,
+}
+
+/// Handler for `POST /users/import`.
+///
+/// Reads users from a body of newline delimited JSON, one [`NewUser`] per
+/// line, and inserts them in transactions of [`IMPORT_BATCH_SIZE`]. The body
+/// is read as it arrives so only a batch is held in memory at a time.
+///
+/// Invalid lines are reported in the response with their line number. Users
+/// inserted before an error stay inserted, even with `stop_on_error`.
+pub async fn import_users(
+    Users(users): Users,
+    Extension(state): Extension<SharedState>,
+    QueryParams(params): QueryParams<ImportParams>,
+    RawBody(mut body): RawBody<Body>,
+) -> Result<(HeaderMap, Json<ImportReport>), AppError> {
+    let mut importer = Importer {
+        users,
+        batch: Vec::with_capacity(IMPORT_BATCH_SIZE),
+        report: ImportReport::default(),
+        params,
+        state,
+    };
+
+    let mut buf = Vec::new();
+    let mut line = 0;
+    // set once the current line is too long, we drop the rest of it
+    let mut too_long = false;
+
+    'body: while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| AppError::new(StatusCode::BAD_REQUEST, err.to_string()))?;
+        let mut chunk = &chunk[..];
+
+        while let Some(end) = chunk.iter().position(|&b| b == b'\n') {
+            if too_long || buf.len() + end > MAX_IMPORT_LINE_LEN {
+                buf = Vec::new();
+                too_long = true;
+            } else {
+                buf.extend_from_slice(&chunk[..end]);
+            }
+            chunk = &chunk[end + 1..];
+
+            line += 1;
+            let bytes = std::mem::take(&mut buf);
+            if !importer
+                .line(line, &bytes, std::mem::take(&mut too_long))
+                .await?
+            {
+                break 'body;
+            }
+        }
+
+        if !too_long {
+            buf.extend_from_slice(chunk);
+            if buf.len() > MAX_IMPORT_LINE_LEN {
+                buf = Vec::new();
+                too_long = true;
+            }
+        }
+    }
+
+    // the last line doesn't have to end with a newline
+    if !importer.report.stopped && (!buf.is_empty() || too_long) {
+        importer.line(line + 1, &buf, too_long).await?;
+    }
+    importer.flush().await?;
+
+    let mut headers = HeaderMap::new();
+    if importer.params.dry_run {
+        headers.insert("x-dry-run", HeaderValue::from_static("true"));
+    }
+
+    Ok((headers, Json(importer.report)))
+}
+
+struct Importer {
+    users: Box<dyn UserRepository>,
+    batch: Vec<NewUser>,
+    report: ImportReport,
+    params: ImportParams,
+    state: SharedState,
+}
+
+impl Importer {
+    /// Handle line number `line`, returning whether to carry on.
+    async fn line(&mut self, line: usize, bytes: &[u8], too_long: bool) -> Result<bool, AppError> {
+        let user = if too_long {
+            Err(format!("line is longer than {} bytes", MAX_IMPORT_LINE_LEN))
+        } else if bytes.iter().all(u8::is_ascii_whitespace) {
+            return Ok(true);
+        } else {
+            self.parse(bytes)
+        };
+
+        match user {
+            Ok(user) => {
+                self.batch.push(user);
+                if self.batch.len() == IMPORT_BATCH_SIZE {
+                    self.flush().await?;
+                }
+                Ok(true)
+            }
+            Err(message) => {
+                self.report.errors.push(ImportError { line, message });
+                self.report.stopped = self.params.stop_on_error;
+                Ok(!self.params.stop_on_error)
+            }
+        }
+    }
+
+    fn parse(&self, bytes: &[u8]) -> Result<NewUser, String> {
+        let value = serde_json::from_slice(bytes).map_err(|err| err.to_string())?;
+        let user: NewUser =
+            from_json(value, &self.state.config).map_err(|err| err.message().to_string())?;
+        user.validate().map_err(|err| err.message().to_string())?;
+        Ok(user)
+    }
+
+    /// Insert the users read so far.
+    async fn flush(&mut self) -> Result<(), AppError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        self.report.imported += self
+            .users
+            .create_many(&self.batch, self.params.dry_run)
+            .await?;
+        self.batch.clear();
+        Ok(())
+    }
+}
+
+/// What the client wants back, from the `Prefer` header (RFC 7240).
+///
+/// Unlike extracting `HeaderMap` this leaves the headers in place for the
+/// extractors that run after it.
+pub enum ReturnPreference {
+    /// `return=representation`, or no preference.
+    Representation,
+    /// `return=minimal`.
+    Minimal,
+    /// `return=count`, which isn't in the RFC. Only updates and deletes
+    /// support it, responding with [`Affected`].
+    Count,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for ReturnPreference
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        // the first one we know about wins
+        let preference = req
+            .headers()
+            .into_iter()
+            .flat_map(|headers| headers.get_all("prefer"))
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(
+                |preference| match preference.trim().to_ascii_lowercase().as_str() {
+                    "return=representation" => Some(Self::Representation),
+                    "return=minimal" => Some(Self::Minimal),
+                    "return=count" => Some(Self::Count),
+                    _ => None,
+                },
+            );
+
+        Ok(preference.unwrap_or(Self::Representation))
+    }
+}
+
+/// The `Idempotency-Key` header, if there is one.
+///
+/// Like [`ReturnPreference`] this leaves the headers in place.
+pub struct IdempotencyKey(pub Option<String>);
+
+impl IdempotencyKey {
+    const MAX_LEN: usize = 255;
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for IdempotencyKey
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let value = match req
+            .headers()
+            .and_then(|headers| headers.get("idempotency-key"))
+        {
+            Some(value) => value,
+            None => return Ok(Self(None)),
+        };
+
+        // `to_str` only accepts printable ASCII
+        match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= Self::MAX_LEN => {
+                Ok(Self(Some(key.to_string())))
+            }
+            _ => Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "`Idempotency-Key` must be 1 to {} printable ASCII characters",
+                    Self::MAX_LEN
+                ),
+            )),
+        }
+    }
+}
+
+/// Handler for `PUT /users/:id`.
+pub async fn replace_user(
+    Users(users): Users,
+    Extension(state): Extension<SharedState>,
+    id: UserId,
+    QueryParams(params): QueryParams<MutationParams>,
+    preference: ReturnPreference,
+    JsonBody(new_user): JsonBody<NewUser>,
+) -> Result<Response<BoxBody>, AppError> {
+    new_user.validate()?;
+
+    let changes = UpdateUser {
+        name: Some(new_user.name),
+        age: Some(new_user.age),
+    };
+    update(users, &state, id, changes, params.dry_run, preference).await
+}
+
+/// Handler for `PATCH /users/:id`.
+pub async fn patch_user(
+    Users(users): Users,
+    Extension(state): Extension<SharedState>,
+    id: UserId,
+    QueryParams(params): QueryParams<MutationParams>,
+    preference: ReturnPreference,
+    JsonBody(changes): JsonBody<UpdateUser>,
+) -> Result<Response<BoxBody>, AppError> {
+    changes.validate()?;
+
+    update(users, &state, id, changes, params.dry_run, preference).await
+}
+
+/// Update the user with `id`, responding with them, or with how many users
+/// changed for `Prefer: return=count`. That is zero if they already had the
+/// changes, while a user that doesn't exist is `404 Not Found` either way.
+async fn update(
+    mut users: Box<dyn UserRepository>,
+    state: &SharedState,
+    id: UserId,
+    changes: UpdateUser,
+    dry_run: bool,
+    preference: ReturnPreference,
+) -> Result<Response<BoxBody>, AppError> {
+    let Updated { user, affected } = users
+        .update(id, &changes, dry_run)
+        .await?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "user not found"))?;
+
+    if !dry_run {
+        state.cache.insert(user.clone());
+    }
+
+    let (status, headers, user) = mutation_response(StatusCode::OK, dry_run, user);
+    if let ReturnPreference::Count = preference {
+        return Ok(affected_response(headers, affected));
+    }
+    Ok((status, headers, user).into_response().map(box_body))
+}
+
+/// Handler for `DELETE /users/:id`.
+///
+/// Responds with an empty body, or with how many users were deleted for
+/// `Prefer: return=count`.
+pub async fn delete_user(
+    Users(mut users): Users,
+    Extension(state): Extension<SharedState>,
+    id: UserId,
+    QueryParams(params): QueryParams<MutationParams>,
+    preference: ReturnPreference,
+) -> Result<Response<BoxBody>, AppError> {
+    let deleted = users.delete(id, params.dry_run).await?;
+    if deleted == 0 {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "user not found"));
+    }
+
+    let mut headers = HeaderMap::new();
+    if params.dry_run {
+        headers.insert("x-dry-run", HeaderValue::from_static("true"));
+    } else {
+        state.cache.remove(id);
+    }
+
+    if let ReturnPreference::Count = preference {
+        return Ok(affected_response(headers, deleted));
+    }
+    Ok((StatusCode::NO_CONTENT, headers)
+        .into_response()
+        .map(box_body))
+}
+
+fn mutation_response(status: StatusCode, dry_run: bool, user: User) -> MutationResponse {
+    let mut headers = HeaderMap::new();
+
+    if dry_run {
+        headers.insert("x-dry-run", HeaderValue::from_static("true"));
+        (StatusCode::OK, headers, Json(user))
+    } else {
+        (status, headers, Json(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_IMPORT_LINE_LEN;
+    use crate::{
+        app,
+        repository::MockUsers,
+        test_helpers::{mock_state, send, test_db, TestDb},
+        AppState, Config,
+    };
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn user_count(db: &TestDb) -> i64 {
+        db.client()
+            .await
+            .query_one("select count(*) from users", &[])
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    fn create_request(uri: &str, body: Value) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_user() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        let response = app(db.state())
+            .oneshot(create_request(
+                "/users",
+                json!({ "name": "alice", "age": 30 }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(response.headers().get("x-dry-run").is_none());
+        assert_eq!(user_count(&db).await, 1);
+    }
+
+    fn create_with_key(key: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/users")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("idempotency-key", key)
+            .body(Body::from(
+                json!({ "name": "alice", "age": 30 }).to_string(),
+            ))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn creates_with_the_same_key_create_one_user() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let state = db.state();
+
+        for _ in 0..2 {
+            let response = app(state.clone())
+                .oneshot(create_with_key("signup-1"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert_eq!(response.headers()[header::LOCATION], "/1");
+        }
+        assert_eq!(user_count(&db).await, 1);
+
+        let response = app(state).oneshot(create_with_key("")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn creates_are_retried_after_losing_the_connection_only_with_a_key() {
+        let users = MockUsers::default();
+        let state = Arc::new(AppState::mock(Config::from_env().unwrap(), users.clone()));
+
+        // the insert went through before the connection was lost, the retry
+        // finds it by the key rather than creating another user
+        users.lose_connection_after_next_create();
+        let response = app(state.clone())
+            .oneshot(create_with_key("signup-1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[header::LOCATION], "/1");
+
+        // without a key we don't know if it's safe to try again
+        users.lose_connection_after_next_create();
+        let (status, _) = send(
+            app(state.clone()),
+            Method::POST,
+            "/users",
+            Some(json!({ "name": "bob", "age": 40 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let (_, body) = send(app(state), Method::GET, "/users", None).await;
+        let names = body["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn users_are_found_by_their_exact_name() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute(
+                "insert into users (name, age) values ('alice', 30), ('Ada Lovelace', 36), \
+                 ('bob', 40), ('bob', 41)",
+            )
+            .await
+            .unwrap();
+        let state = db.state();
+
+        let (status, body) = send(
+            app(state.clone()),
+            Method::GET,
+            "/users/by-name/Ada%20Lovelace",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "id": 2, "name": "Ada Lovelace", "age": 36 }));
+
+        for name in vec!["Alice", "ali", "carol"] {
+            let uri = format!("/users/by-name/{}", name);
+            let (status, _) = send(app(state.clone()), Method::GET, &uri, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", name);
+        }
+
+        let (status, _) = send(app(state), Method::GET, "/users/by-name/bob", None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn users_are_created_or_updated_by_name() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let state = db.state();
+
+        let (status, created) = send(
+            app(state.clone()),
+            Method::PUT,
+            "/users/by-name/alice",
+            Some(json!({ "age": 30 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created, json!({ "id": 1, "name": "alice", "age": 30 }));
+
+        let (status, updated) = send(
+            app(state.clone()),
+            Method::PUT,
+            "/users/by-name/alice",
+            Some(json!({ "age": 31 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(updated, json!({ "id": 1, "name": "alice", "age": 31 }));
+        assert_eq!(user_count(&db).await, 1);
+
+        // only one of them gets to insert
+        let upserts = (0..10)
+            .map(|age| {
+                tokio::spawn(send(
+                    app(state.clone()),
+                    Method::PUT,
+                    "/users/by-name/bob",
+                    Some(json!({ "age": age })),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let mut created = 0;
+        for upsert in upserts {
+            match upsert.await.unwrap() {
+                (StatusCode::CREATED, _) => created += 1,
+                (status, _) => assert_eq!(status, StatusCode::OK),
+            }
+        }
+        assert_eq!(created, 1);
+        assert_eq!(user_count(&db).await, 2);
+
+        db.client()
+            .await
+            .batch_execute("insert into users (name, age) values ('carol', 20), ('carol', 21)")
+            .await
+            .unwrap();
+        let (status, _) = send(
+            app(state),
+            Method::PUT,
+            "/users/by-name/carol",
+            Some(json!({ "age": 50 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let ages = db
+            .client()
+            .await
+            .query(
+                "select age from users where name = 'carol' order by id",
+                &[],
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<_, i32>(0))
+            .collect::<Vec<_>>();
+        assert_eq!(ages, vec![20, 21]);
+    }
+
+    #[tokio::test]
+    async fn mock_users_are_created_or_updated_by_name() {
+        let state = mock_state();
+
+        let (status, body) = send(
+            app(state.clone()),
+            Method::PUT,
+            "/users/by-name/alice?dry_run=true",
+            Some(json!({ "age": 30 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "alice");
+
+        for (age, expected_status) in vec![(30, StatusCode::CREATED), (31, StatusCode::OK)] {
+            let (status, body) = send(
+                app(state.clone()),
+                Method::PUT,
+                "/users/by-name/alice",
+                Some(json!({ "age": age })),
+            )
+            .await;
+            assert_eq!(status, expected_status);
+            assert_eq!(body["age"], age);
+        }
+
+        let (status, body) = send(app(state), Method::GET, "/users", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["users"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dry_run_create_persists_nothing() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        let response = app(db.state())
+            .oneshot(create_request(
+                "/users?dry_run=true",
+                json!({ "name": "alice", "age": 30 }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-dry-run"], "true");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["name"], "alice");
+        assert_eq!(body["age"], 30);
+
+        assert_eq!(user_count(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn out_of_range_age_is_bad_request() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        let response = app(db.state())
+            .oneshot(create_request(
+                "/users",
+                json!({ "name": "alice", "age": 151 }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], &b"age must be between 0 and 150"[..]);
+        assert_eq!(user_count(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_fields_can_be_rejected() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        let config = Config {
+            reject_unknown_fields: true,
+            ..Config::from_env().unwrap()
+        };
+
+        let response = app(db.state_with(config).await)
+            .oneshot(create_request(
+                "/users",
+                json!({ "name": "alice", "nmae": "alice", "age": 30 }),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            &body[..],
+            &b"unknown field `nmae`, expected one of name, age"[..]
+        );
+        assert_eq!(user_count(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn bodies_must_be_utf8_json() {
+        let state = mock_state();
+
+        for (content_type, body, expected_status, expected_body) in vec![
+            (
+                "application/json",
+                &b"{\"name\": \"ren\xe9\", \"age\": 30}"[..],
+                StatusCode::BAD_REQUEST,
+                "body is not valid UTF-8 JSON",
+            ),
+            (
+                "application/json; charset=iso-8859-1",
+                &b"{\"name\": \"ren\xe9\", \"age\": 30}"[..],
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported charset `iso-8859-1`, bodies must be UTF-8",
+            ),
+            (
+                "application/json; charset=\"UTF-8\"",
+                "{\"name\": \"ren\u{e9}\", \"age\": 30}".as_bytes(),
+                StatusCode::CREATED,
+                "",
+            ),
+        ] {
+            let response = app(state.clone())
+                .oneshot(
+                    Request::post("/users")
+                        .header(header::CONTENT_TYPE, content_type)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected_status, "{}", content_type);
+
+            if !expected_body.is_empty() {
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                assert_eq!(body, expected_body);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn list_is_capped_at_max_rows() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute(
+                "insert into users (name, age) values ('alice', 30), ('bob', 40), ('carol', 50)",
+            )
+            .await
+            .unwrap();
+
+        for (max_rows, expected_names, expected_truncated) in vec![
+            (2, json!(["alice", "bob"]), true),
+            (3, json!(["alice", "bob", "carol"]), false),
+        ] {
+            let config = Config {
+                max_rows,
+                ..Config::from_env().unwrap()
+            };
+
+            let response = app(db.state_with(config).await)
+                .oneshot(
+                    Request::builder()
+                        .uri("/users")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let names = body["users"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|user| user["name"].clone())
+                .collect::<Vec<_>>();
+            assert_eq!(json!(names), expected_names);
+            assert_eq!(body["has_more"], expected_truncated);
+            assert_eq!(body["truncated"], expected_truncated);
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_parameters_are_bad_request() {
+        let state = mock_state();
+        send(
+            app(state.clone()),
+            Method::POST,
+            "/users",
+            Some(json!({ "name": "alice", "age": 30 })),
+        )
+        .await;
+
+        for (method, uri) in vec![
+            (Method::GET, "/one"),
+            (Method::GET, "/1?fields="),
+            (Method::GET, "/users?limit=ten"),
+            (Method::GET, "/users?offset=-1"),
+            (Method::GET, "/users?offset=9223372036854775808"),
+            (Method::POST, "/users?dry_run=maybe"),
+            (Method::POST, "/users/import?stop_on_error=2"),
+            (Method::GET, "/users/one/similar"),
+            (Method::GET, "/users/1/similar?limit=-1"),
+            (Method::PATCH, "/users/one"),
+            (Method::PUT, "/users/1?dry_run=yes"),
+            (Method::DELETE, "/users/1.5"),
+        ] {
+            let response = app(state.clone())
+                .oneshot(
+                    Request::builder()
+                        .method(method.clone())
+                        .uri(uri)
+                        // only our own errors are turned into problem details
+                        .header(header::ACCEPT, "application/problem+json")
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(json!({ "name": "bob", "age": 40 }).to_string()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::BAD_REQUEST,
+                "{} {}",
+                method,
+                uri
+            );
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/problem+json",
+                "{} {}",
+                method,
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_without_a_limit_use_the_default_page_limit() {
+        let config = Config {
+            default_page_limit: 2,
+            ..Config::from_env().unwrap()
+        };
+        let state = Arc::new(AppState::mock(config, MockUsers::default()));
+        for name in vec!["alice", "bob", "carol", "dave"] {
+            let user = json!({ "name": name, "age": 30 });
+            send(app(state.clone()), Method::POST, "/users", Some(user)).await;
+        }
+
+        let (status, body) = send(app(state.clone()), Method::GET, "/users", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["users"].as_array().unwrap().len(), 2);
+
+        let (status, body) = send(app(state.clone()), Method::GET, "/users/1/similar", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+
+        let (status, body) = send(
+            app(state.clone()),
+            Method::GET,
+            "/users/1/similar?limit=3",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 3);
+
+        let (status, _) = send(app(state), Method::GET, "/users/1/similar?limit=0", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn list_links_to_next_and_previous_pages() {
+        let state = mock_state();
+        for name in vec!["alice", "bob", "carol", "dave", "erin"] {
+            let user = json!({ "name": name, "age": 30 });
+            send(app(state.clone()), Method::POST, "/users", Some(user)).await;
+        }
+
+        for (offset, expected_names, expected_links) in vec![
+            (
+                0,
+                json!(["alice", "bob"]),
+                Some(r#"</users?limit=2&offset=2>; rel="next""#),
+            ),
+            (
+                2,
+                json!(["carol", "dave"]),
+                Some(
+                    r#"</users?limit=2&offset=4>; rel="next", </users?limit=2&offset=0>; rel="prev""#,
+                ),
+            ),
+            (
+                4,
+                json!(["erin"]),
+                Some(r#"</users?limit=2&offset=2>; rel="prev""#),
+            ),
+        ] {
+            let response = app(state.clone())
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/users?limit=2&offset={}", offset))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let links = response
+                .headers()
+                .get(header::LINK)
+                .map(|links| links.to_str().unwrap().to_string());
+            assert_eq!(links.as_deref(), expected_links, "offset {}", offset);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let names = body["users"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|user| user["name"].clone())
+                .collect::<Vec<_>>();
+            assert_eq!(json!(names), expected_names);
+            assert_eq!(body["has_more"], expected_links.unwrap().contains("next"));
+            assert_eq!(body["total"], 5);
+        }
+
+        // everything fits on one page
+        let response = app(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/users")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::LINK).is_none());
+    }
+
+    #[tokio::test]
+    async fn create_respects_prefer_return() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        for (prefer, expect_body) in vec![
+            (None, true),
+            (Some("return=representation"), true),
+            (Some("return=minimal"), false),
+        ] {
+            let mut request = create_request("/users", json!({ "name": "alice", "age": 30 }));
+            if let Some(prefer) = prefer {
+                request
+                    .headers_mut()
+                    .insert("prefer", prefer.parse().unwrap());
+            }
+
+            let response = app(db.state()).oneshot(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert!(response.headers().contains_key(header::LOCATION));
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            if expect_body {
+                let body: Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(body["name"], "alice", "{:?}", prefer);
+            } else {
+                assert!(body.is_empty(), "{:?}", prefer);
+            }
+        }
+    }
+
+    async fn get_similar(db: &TestDb, id: i32) -> (StatusCode, Value) {
+        let response = app(db.state())
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/users/{}/similar?limit=5", id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn similar_users_of_missing_user_is_not_found() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        let (status, _) = get_similar(&db, 1).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn similar_users_are_ordered_by_age_distance() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute(
+                "insert into users (name, age) values \
+                 ('alice', 30), ('bob', 45), ('carol', 28), ('dave', 90), \
+                 ('erin', 33), ('frank', 31), ('grace', 60)",
+            )
+            .await
+            .unwrap();
+
+        let (status, body) = get_similar(&db, 1).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let names = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["frank", "carol", "erin", "bob", "grace"]);
+    }
+
+    #[tokio::test]
+    async fn large_lookups_are_chunked_and_deduplicated() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute(
+                "insert into users (name, age) \
+                 select 'user ' || i, i from generate_series(1, 25) as i; \
+                 update users set deleted_at = now() where id = 7",
+            )
+            .await
+            .unwrap();
+        let config = Config {
+            lookup_chunk_size: 4,
+            max_lookup_ids: 40,
+            ..Config::from_env().unwrap()
+        };
+        let state = db.state_with(config).await;
+
+        // newest first, repeating some and with a few that don't exist
+        let mut ids = (1..=25).rev().map(|id| json!(id)).collect::<Vec<_>>();
+        ids.extend(vec![json!(1), json!("2"), json!(25), json!(99), json!(100)]);
+        let (status, body) = send(
+            app(state.clone()),
+            Method::POST,
+            "/users/lookup",
+            Some(json!({ "ids": ids })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let ids = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["id"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        let expected = (1..=25).rev().filter(|&id| id != 7).collect::<Vec<_>>();
+        assert_eq!(ids, expected);
+
+        let (status, _) = send(
+            app(state),
+            Method::POST,
+            "/users/lookup",
+            Some(json!({ "ids": (0..41).collect::<Vec<_>>() })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    fn bulk_update_request(body: Value) -> Request<Body> {
+        let mut request = create_request("/users/bulk-update", body);
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        request
+    }
+
+    fn admin_config() -> Config {
+        Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::from_env().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk_update_only_touches_filtered_users() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute(
+                "insert into users (name, age) values ('alice', 15), ('bob', 17), ('carol', 40)",
+            )
+            .await
+            .unwrap();
+
+        let response = app(db.state_with(admin_config()).await)
+            .oneshot(bulk_update_request(
+                json!({ "max_age": 17, "increment_age": 1 }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["updated"], 2);
+
+        let ages = db
+            .client()
+            .await
+            .query("select age from users order by id", &[])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect::<Vec<i32>>();
+        assert_eq!(ages, vec![16, 18, 40]);
+    }
+
+    #[tokio::test]
+    async fn updates_without_changes_affect_no_rows() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute("insert into users (name, age) values ('alice', 30)")
+            .await
+            .unwrap();
+        let state = db.state();
+
+        for (body, expected) in vec![
+            (json!({ "age": 30 }), 0),
+            (json!({ "name": "alice" }), 0),
+            (json!({ "name": "alice", "age": 31 }), 1),
+        ] {
+            let mut request = create_request("/users/1", body.clone());
+            *request.method_mut() = Method::PATCH;
+            request
+                .headers_mut()
+                .insert("prefer", "return=count".parse().unwrap());
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", body);
+            let response = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response: Value = serde_json::from_slice(&response).unwrap();
+            assert_eq!(response, json!({ "affected": expected }), "{}", body);
+        }
+
+        // the user still comes back when nothing changed
+        let (status, body) = send(
+            app(state),
+            Method::PATCH,
+            "/users/1",
+            Some(json!({ "age": 31 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "alice");
+        assert_eq!(body["age"], 31);
+    }
+
+    #[tokio::test]
+    async fn mutations_can_respond_with_affected_counts() {
+        let state = Arc::new(AppState::mock(admin_config(), MockUsers::default()));
+        for (name, age) in vec![("alice", 15), ("bob", 17), ("carol", 40)] {
+            let user = json!({ "name": name, "age": age });
+            send(app(state.clone()), Method::POST, "/users", Some(user)).await;
+        }
+
+        let affected = |mut request: Request<Body>| {
+            request
+                .headers_mut()
+                .insert("prefer", "return=count".parse().unwrap());
+            let app = app(state.clone());
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()["preference-applied"], "return=count");
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+
+        let update = json!({ "max_age": 17, "increment_age": 1 });
+        let body = affected(bulk_update_request(update)).await;
+        assert_eq!(body, json!({ "affected": 2 }));
+        // nobody is that young
+        let update = json!({ "max_age": 1, "increment_age": 1 });
+        let body = affected(bulk_update_request(update)).await;
+        assert_eq!(body, json!({ "affected": 0 }));
+
+        let patch = |body| {
+            let mut request = create_request("/users/1", body);
+            *request.method_mut() = Method::PATCH;
+            request
+        };
+        assert_eq!(
+            affected(patch(json!({ "age": 20 }))).await,
+            json!({ "affected": 1 })
+        );
+        // already that old
+        assert_eq!(
+            affected(patch(json!({ "age": 20 }))).await,
+            json!({ "affected": 0 })
+        );
+        let delete = Request::delete("/users/2").body(Body::empty()).unwrap();
+        assert_eq!(affected(delete).await, json!({ "affected": 1 }));
+
+        // single users still come back by default
+        let (status, body) = send(
+            app(state),
+            Method::PATCH,
+            "/users/1",
+            Some(json!({ "age": 21 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["age"], 21);
+    }
+
+    #[tokio::test]
+    async fn fields_can_be_named_in_camel_case() {
+        for json_camel_case in vec![false, true] {
+            let config = Config {
+                json_camel_case,
+                ..admin_config()
+            };
+            let state = Arc::new(AppState::mock(config, MockUsers::default()));
+            let alice = json!({ "name": "alice", "age": 15 });
+            send(app(state.clone()), Method::POST, "/users", Some(alice)).await;
+
+            // only the convention we respond with is understood, the other
+            // leaves out the filter
+            for (body, understood) in vec![
+                (
+                    json!({ "max_age": 17, "increment_age": 1 }),
+                    !json_camel_case,
+                ),
+                (json!({ "maxAge": 17, "incrementAge": 1 }), json_camel_case),
+            ] {
+                let response = app(state.clone())
+                    .oneshot(bulk_update_request(body))
+                    .await
+                    .unwrap();
+                let expected = if understood {
+                    StatusCode::OK
+                } else {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                };
+                assert_eq!(response.status(), expected, "{}", json_camel_case);
+            }
+
+            let response = app(state)
+                .oneshot(
+                    Request::builder()
+                        .uri("/debug/config")
+                        .header(header::AUTHORIZATION, "Bearer secret")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let (field, other) = if json_camel_case {
+                ("jsonCamelCase", "json_camel_case")
+            } else {
+                ("json_camel_case", "jsonCamelCase")
+            };
+            assert_eq!(body[field], json_camel_case);
+            assert!(body.get(other).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk_update_without_filter_requires_all() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        db.client()
+            .await
+            .batch_execute("insert into users (name, age) values ('alice', 15), ('bob', 40)")
+            .await
+            .unwrap();
+        let state = db.state_with(admin_config()).await;
+
+        let response = app(state.clone())
+            .oneshot(bulk_update_request(json!({ "name": "anonymous" })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = app(state)
+            .oneshot(bulk_update_request(
+                json!({ "all": true, "name": "anonymous" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let renamed: i64 = db
+            .client()
+            .await
+            .query_one("select count(*) from users where name = 'anonymous'", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(renamed, 2);
+    }
+
+    async fn import(db: &TestDb, uri: &str, body: impl Into<Body>) -> Value {
+        let response = app(db.state())
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/x-ndjson")
+                    .body(body.into())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    const NDJSON: &str = "{\"name\": \"alice\", \"age\": 30}\n\
+                          {\"name\": \"bob\", \"age\": 200}\n\
+                          \n\
+                          {\"name\": \"carol\", \"age\": 50}";
+
+    #[tokio::test]
+    async fn import_skips_invalid_lines() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        let report = import(&db, "/users/import", NDJSON).await;
+
+        assert_eq!(
+            report,
+            json!({
+                "imported": 2,
+                "errors": [{ "line": 2, "message": "age must be between 0 and 150" }],
+                "stopped": false,
+            })
+        );
+        assert_eq!(user_count(&db).await, 2);
+    }
+
+    #[tokio::test]
+    async fn import_can_stop_on_error() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+
+        let report = import(&db, "/users/import?stop_on_error=true", NDJSON).await;
+
+        assert_eq!(report["imported"], 1);
+        assert_eq!(report["errors"][0]["line"], 2);
+        assert_eq!(report["stopped"], true);
+        assert_eq!(user_count(&db).await, 1);
+    }
+
+    #[tokio::test]
+    async fn import_rejects_long_lines_within_a_chunk() {
+        let db = match test_db().await {
+            Some(db) => db,
+            None => return,
+        };
+        // all in one chunk, so the long line never makes it to the end of one
+        let body = format!(
+            "{{\"name\": \"alice\", \"age\": 30}}\n\
+             {{\"name\": \"{}\", \"age\": 40}}\n\
+             {{\"name\": \"carol\", \"age\": 50}}\n",
+            "b".repeat(MAX_IMPORT_LINE_LEN)
+        );
+
+        let report = import(&db, "/users/import", body).await;
+
+        assert_eq!(
+            report,
+            json!({
+                "imported": 2,
+                "errors": [{
+                    "line": 2,
+                    "message": format!("line is longer than {} bytes", MAX_IMPORT_LINE_LEN),
+                }],
+                "stopped": false,
+            })
+        );
+        assert_eq!(user_count(&db).await, 2);
+    }
+}